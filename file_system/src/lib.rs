@@ -0,0 +1,3976 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use fuser::{ FileAttr, FileType, Filesystem, Request, ReplyDirectory, ReplyAttr, ReplyData, ReplyEntry, ReplyEmpty, ReplyOpen, ReplyCreate, ReplyWrite, ReplyStatfs, ReplyXattr, ReplyIoctl, ReplyLseek };
+use libc::{ENOENT};
+
+
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+
+
+pub const BLOCK_COUNT: u64 = 2048;
+pub const BLOCK_SIZE: u64 = 512;
+const MAX_NAME_SIZE: usize = 25;
+// 'Q' leaves bit 1 clear, while the first byte of a pre-superblock disk (its bitmap) always has it set
+const MAGIC: [u8; 8] = *b"QRFSSUPR";
+const SUPERBLOCK_LEN: usize = 64;
+const ENTRY_HEADER_LEN: usize = 120;
+const MIN_BLOCK_SIZE: u64 = (ENTRY_HEADER_LEN as u64 + 8).next_multiple_of(8);
+const INODE_COUNTER_OFFSET: u64 = 48;
+const FORMAT_VERSION_OFFSET: u64 = 56;
+// disks made before the superblock kept the bitmap in block 0 and these in block 1
+const LEGACY_INODE_COUNTER_OFFSET: u64 = BLOCK_SIZE;
+const LEGACY_FORMAT_VERSION_OFFSET: u64 = BLOCK_SIZE + 8;
+// 2: nlink in the entry header, 3: uid/gid, 4: full file type in the kind byte, 5: nanosecond timestamps, 6: rdev
+const FORMAT_VERSION: u64 = 6;
+pub const DEFAULT_MAX_DIR_ENTRIES: usize = 10_000;
+const DIRTY_LIMIT: usize = 64;
+const DIRTY_INTERVAL: Duration = Duration::from_secs(5);
+const XATTR_NAME_MAX: usize = 255;
+const XATTR_SIZE_MAX: usize = 65536;
+const FS_IMMUTABLE_FL: u32 = 0x10;
+const FS_IOC_FSGETXATTR: u32 = 0x801c581f;
+const FS_XFLAG_IMMUTABLE: u32 = 0x8;
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+
+fn to_seconds(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+
+fn u64_to_systemtime(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+
+fn subsec_nanos(t: SystemTime) -> u32 {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos()
+}
+
+
+fn time_or_now(t: fuser::TimeOrNow) -> SystemTime {
+    match t {
+        fuser::TimeOrNow::SpecificTime(t) => t,
+        fuser::TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+
+// 0 and 1 match the old is_directory flag
+fn kind_to_byte(kind: FileType) -> u8 {
+    match kind {
+        FileType::RegularFile => 0,
+        FileType::Directory => 1,
+        FileType::Symlink => 2,
+        FileType::NamedPipe => 3,
+        FileType::CharDevice => 4,
+        FileType::BlockDevice => 5,
+        FileType::Socket => 6,
+    }
+}
+
+
+fn byte_to_kind(b: u8) -> FileType {
+    match b {
+        1 => FileType::Directory,
+        2 => FileType::Symlink,
+        3 => FileType::NamedPipe,
+        4 => FileType::CharDevice,
+        5 => FileType::BlockDevice,
+        6 => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+
+fn serialize_fs_entry_to_disk(f: &FSEntry) -> Vec<u8> {
+    let mut buf = Vec::new();
+    
+    buf.extend_from_slice(&f.inode.to_le_bytes());
+    buf.extend_from_slice(&f.parent.to_le_bytes());
+    buf.extend_from_slice(&f.name);
+
+    buf.push(kind_to_byte(f.attrs.kind));
+
+    buf.extend_from_slice(&f.attrs.perm.to_le_bytes());
+    buf.extend_from_slice(&to_seconds(f.attrs.atime).to_le_bytes());
+    buf.extend_from_slice(&to_seconds(f.attrs.mtime).to_le_bytes());
+    buf.extend_from_slice(&to_seconds(f.attrs.ctime).to_le_bytes());
+    buf.extend_from_slice(&to_seconds(f.attrs.crtime).to_le_bytes());
+    buf.extend_from_slice(&f.attrs.blksize.to_le_bytes());
+    buf.extend_from_slice(&f.attrs.size.to_le_bytes());
+    buf.extend_from_slice(&f.attrs.nlink.to_le_bytes());
+    buf.extend_from_slice(&f.attrs.uid.to_le_bytes());
+    buf.extend_from_slice(&f.attrs.gid.to_le_bytes());
+    for t in [f.attrs.atime, f.attrs.mtime, f.attrs.ctime, f.attrs.crtime] {
+        buf.extend_from_slice(&subsec_nanos(t).to_le_bytes());
+    }
+    buf.extend_from_slice(&f.attrs.rdev.to_le_bytes());
+    
+    if let Some(slice) = &f.data {
+        buf.extend_from_slice(slice);
+    }
+
+    // older entries end in zero padding here, which reads back as no links, no xattrs and no flags
+    buf.extend_from_slice(&(f.links.len() as u32).to_le_bytes());
+    for (link_parent, link_name) in &f.links {
+        buf.extend_from_slice(&link_parent.to_le_bytes());
+        buf.extend_from_slice(link_name);
+    }
+
+    buf.extend_from_slice(&(f.xattrs.len() as u32).to_le_bytes());
+    for (xattr_name, value) in &f.xattrs {
+        buf.extend_from_slice(&(xattr_name.len() as u32).to_le_bytes());
+        buf.extend_from_slice(xattr_name.as_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+    }
+
+    buf.extend_from_slice(&f.attrs.flags.to_le_bytes());
+
+    buf
+}
+
+
+// must stay in step with serialize_fs_entry_to_disk
+fn serialized_len(f: &FSEntry) -> usize {
+    let data_len = f.data.as_ref().map_or(0, |d| d.len());
+    let links_len = 4 + f.links.len() * (8 + MAX_NAME_SIZE);
+    let xattrs_len: usize = 4 + f.xattrs.iter().map(|(n, v)| 8 + n.len() + v.len()).sum::<usize>();
+    ENTRY_HEADER_LEN + data_len + links_len + xattrs_len + 4
+}
+
+
+fn deserialize_fs_entry(buf: &[u8], version: u64) -> FSEntry {
+    let mut offset = 0;
+
+    let file_inode = u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap());
+    offset+=8;
+    let file_parent = u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap());
+    offset+=8;
+
+    let mut file_name = [0u8; MAX_NAME_SIZE];
+    file_name.copy_from_slice(&buf[offset..offset+MAX_NAME_SIZE]);
+    offset+=MAX_NAME_SIZE;
+    
+    let kind_byte = buf[offset];
+    offset+=1;
+    let file_kind = if version >= 4 {
+        byte_to_kind(kind_byte)
+    } else if kind_byte != 0 {
+        FileType::Directory
+    } else { FileType::RegularFile };
+
+    let file_perm = u16::from_le_bytes(buf[offset..offset+2].try_into().unwrap());
+    offset+=2;
+    let mut file_atime = u64_to_systemtime(u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap()));
+    offset+=8;
+    let mut file_mtime = u64_to_systemtime(u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap()));
+    offset+=8;
+    let mut file_ctime = u64_to_systemtime(u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap()));
+    offset+=8;
+    let mut file_crtime = u64_to_systemtime(u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap()));
+    offset+=8;
+    let file_blksize = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+    offset+=4;
+    let file_data_size = u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap());
+    offset+=8;
+    let mut file_nlink = 0;
+    if version >= 2 {
+        file_nlink = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+    }
+    let (mut file_uid, mut file_gid) = (0, 0);
+    if version >= 3 {
+        file_uid = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+        file_gid = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+    }
+    if version >= 5 {
+        for t in [&mut file_atime, &mut file_mtime, &mut file_ctime, &mut file_crtime] {
+            *t += Duration::from_nanos(u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap()) as u64);
+            offset+=4;
+        }
+    }
+    let mut file_rdev = 0;
+    if version >= 6 {
+        file_rdev = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+    }
+
+    let mut attr = FileAttr {
+        ino: file_inode,
+        size: file_data_size,
+        blocks: 0,
+        atime: file_atime,
+        mtime: file_mtime,
+        ctime: file_ctime,
+        crtime: file_crtime,
+        kind: file_kind,
+        perm: file_perm,
+        nlink: file_nlink,
+        uid: file_uid,
+        gid: file_gid,
+        rdev: file_rdev,
+        flags: 0,
+        blksize: file_blksize,
+    };
+
+    let data_end = (offset + file_data_size as usize).min(buf.len());
+    let file_data = &buf[offset..data_end];
+    let file_data_vec = file_data.to_vec();
+    offset = data_end;
+
+    let mut links = Vec::new();
+    if buf.len() >= offset + 4 {
+        let link_count = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+        for _ in 0..link_count {
+            if buf.len() < offset + 8 + MAX_NAME_SIZE {
+                break;
+            }
+            let link_parent = u64::from_le_bytes(buf[offset..offset+8].try_into().unwrap());
+            offset+=8;
+            let mut link_name = [0u8; MAX_NAME_SIZE];
+            link_name.copy_from_slice(&buf[offset..offset+MAX_NAME_SIZE]);
+            offset+=MAX_NAME_SIZE;
+            links.push((link_parent, link_name));
+        }
+    }
+
+    let mut xattrs = HashMap::new();
+    if buf.len() >= offset + 4 {
+        let xattr_count = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+        offset+=4;
+        for _ in 0..xattr_count {
+            if buf.len() < offset + 4 {
+                break;
+            }
+            let name_len = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap()) as usize;
+            offset+=4;
+            if buf.len() < offset + name_len + 4 {
+                break;
+            }
+            let xattr_name = String::from_utf8_lossy(&buf[offset..offset+name_len]).to_string();
+            offset+=name_len;
+            let value_len = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap()) as usize;
+            offset+=4;
+            if buf.len() < offset + value_len {
+                break;
+            }
+            xattrs.insert(xattr_name, buf[offset..offset+value_len].to_vec());
+            offset+=value_len;
+        }
+    }
+
+    if buf.len() >= offset + 4 {
+        attr.flags = u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap());
+    }
+
+    FSEntry { // just for tests
+        inode: file_inode,
+        name: file_name,
+        data: if file_data_vec.is_empty() { None } else { Some(file_data_vec) },
+        parent: file_parent,
+        links,
+        xattrs,
+        children: Vec::new(),
+        child_index: HashMap::new(),
+        attrs: attr,
+    }
+}
+
+
+pub enum Disk {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Disk {
+    fn len(&self) -> std::io::Result<u64> {
+        match self {
+            Disk::File(f) => Ok(f.metadata()?.len()),
+            Disk::Memory(c) => Ok(c.get_ref().len() as u64),
+        }
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        match self {
+            Disk::File(f) => f.set_len(len),
+            Disk::Memory(c) => {
+                c.get_mut().resize(len as usize, 0);
+                Ok(())
+            }
+        }
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        match self {
+            Disk::File(f) => f.sync_all(),
+            Disk::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Disk {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Disk::File(f) => f.read(buf),
+            Disk::Memory(c) => c.read(buf),
+        }
+    }
+}
+
+impl Write for Disk {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Disk::File(f) => f.write(buf),
+            Disk::Memory(c) => c.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Disk::File(f) => f.flush(),
+            Disk::Memory(c) => c.flush(),
+        }
+    }
+}
+
+impl Seek for Disk {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Disk::File(f) => f.seek(pos),
+            Disk::Memory(c) => c.seek(pos),
+        }
+    }
+}
+
+
+fn open_disk(path: &str) -> std::io::Result<Disk> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map(Disk::File)
+}
+
+
+fn open_existing_disk(path: &str) -> std::io::Result<Disk> {
+    OpenOptions::new().read(true).write(true).open(path).map(Disk::File)
+}
+
+
+fn open_disk_read_only(path: &str) -> std::io::Result<Disk> {
+    OpenOptions::new().read(true).open(path).map(Disk::File)
+}
+
+
+fn check_disk_offset(file: &Disk, offset: u64, len: u64) -> std::io::Result<()> {
+    let disk_len = file.len()?;
+    if offset.checked_add(len).is_none_or(|end| end > disk_len) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("offset {} (+{} bytes) is outside the disk ({} bytes)", offset, len, disk_len),
+        ));
+    }
+    Ok(())
+}
+
+
+fn write_u64(file: &mut Disk, offset: u64, v: u64) -> std::io::Result<()> {
+    check_disk_offset(file, offset, 8)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&v.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_u64(file: &mut Disk, offset: u64) -> std::io::Result<u64> {
+    check_disk_offset(file, offset, 8)?;
+    let mut b = [0u8; 8];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct SuperBlock {
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub bitmap_start: u64,
+    pub bitmap_blocks: u64,
+    pub data_start: u64,
+}
+
+impl SuperBlock {
+    pub fn new(total_blocks: u64, block_size: u64) -> Self {
+        let bitmap_blocks = total_blocks.div_ceil(8).div_ceil(block_size).max(1);
+        Self {
+            block_size,
+            total_blocks,
+            bitmap_start: 1,
+            bitmap_blocks,
+            data_start: 1 + bitmap_blocks,
+        }
+    }
+
+    // the last 8 bytes of an entry block point to the next block of its chain, 0 ends it
+    pub fn payload(&self) -> usize {
+        (self.block_size - 8) as usize
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        for v in [self.block_size, self.total_blocks, self.bitmap_start, self.bitmap_blocks, self.data_start] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf[..8] != MAGIC {
+            return None;
+        }
+        let field = |i: usize| u64::from_le_bytes(buf[8 + i * 8..16 + i * 8].try_into().unwrap());
+        Some(Self {
+            block_size: field(0),
+            total_blocks: field(1),
+            bitmap_start: field(2),
+            bitmap_blocks: field(3),
+            data_start: field(4),
+        })
+    }
+}
+
+
+fn invalid_image(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("not a valid QRFS image: {}", reason))
+}
+
+
+fn read_superblock(f: &mut Disk, read_only: bool) -> std::io::Result<SuperBlock> {
+    let disk_len = f.len()?;
+    if disk_len < SUPERBLOCK_LEN as u64 {
+        return Err(invalid_image(format!("the file is only {} bytes", disk_len)));
+    }
+
+    let mut buf = [0u8; SUPERBLOCK_LEN];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut buf)?;
+
+    if let Some(sb) = SuperBlock::from_bytes(&buf) {
+        check_geometry(sb.total_blocks, sb.block_size).map_err(invalid_image)?;
+        let expected = SuperBlock::new(sb.total_blocks, sb.block_size);
+        if (sb.bitmap_start, sb.bitmap_blocks, sb.data_start) != (expected.bitmap_start, expected.bitmap_blocks, expected.data_start) {
+            return Err(invalid_image("the superblock's bitmap and data regions do not match its geometry".to_string()));
+        }
+        if disk_len < sb.total_blocks * sb.block_size {
+            return Err(invalid_image(format!("the file is truncated, {} blocks of {} bytes need {} bytes but it has {}", sb.total_blocks, sb.block_size, sb.total_blocks * sb.block_size, disk_len)));
+        }
+        return Ok(sb);
+    }
+
+    let looks_legacy = buf[0] & 0b11 == 0b11 && disk_len == BLOCK_COUNT * BLOCK_SIZE
+        && read_u64(f, LEGACY_FORMAT_VERSION_OFFSET)? < FORMAT_VERSION;
+    if looks_legacy {
+        if read_only {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "disk predates the superblock, mount it read-write once to convert it"));
+        }
+        return convert_legacy_disk(f);
+    }
+
+    Err(invalid_image("bad superblock magic".to_string()))
+}
+
+
+fn write_superblock(f: &mut Disk, sb: &SuperBlock) -> std::io::Result<()> {
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&sb.to_bytes())?;
+    Ok(())
+}
+
+
+// the old bitmap block becomes the superblock and the old counter block the bitmap, so no entry has to move
+fn convert_legacy_disk(f: &mut Disk) -> std::io::Result<SuperBlock> {
+    println!("Converting disk without a superblock to the superblock layout");
+    let sb = SuperBlock::new(BLOCK_COUNT, BLOCK_SIZE);
+
+    let mut bitmap = vec![0u8; BLOCK_SIZE as usize];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut bitmap)?;
+    let counter = read_u64(f, LEGACY_INODE_COUNTER_OFFSET)?;
+    let version = read_u64(f, LEGACY_FORMAT_VERSION_OFFSET)?;
+
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&[0u8; BLOCK_SIZE as usize])?;
+    write_superblock(f, &sb)?;
+    write_u64(f, INODE_COUNTER_OFFSET, counter)?;
+    write_u64(f, FORMAT_VERSION_OFFSET, version)?;
+    f.sync_all()?;
+    write_bitmap(f, &sb, &bitmap)?;
+    f.sync_all()?;
+    Ok(sb)
+}
+
+
+fn read_bitmap(f: &mut Disk, sb: &SuperBlock) -> std::io::Result<Vec<u8>> {
+    let bitmap_bytes = (sb.bitmap_blocks * sb.block_size) as usize;
+    let mut buf = vec![0u8; bitmap_bytes];
+    let offset = sb.bitmap_start * sb.block_size; 
+    f.seek(SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+
+fn write_bitmap(f: &mut Disk, sb: &SuperBlock, bitmap: &[u8]) -> std::io::Result<()> {
+    let offset = sb.bitmap_start * sb.block_size;
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(bitmap)?;
+    Ok(())
+}
+
+
+fn bitmap_get(bitmap: &[u8], idx: u64) -> bool {
+    let byte_idx = (idx / 8) as usize;
+    let bit = (idx % 8) as u8;
+    if byte_idx >= bitmap.len() {
+        return false;
+    }
+    (bitmap[byte_idx] & (1 << bit)) != 0
+}
+
+
+fn bitmap_set_bit(bitmap: &mut [u8], idx: u64) {
+    let byte_idx = (idx / 8) as usize;
+    let bit = (idx % 8) as u8;
+    if byte_idx < bitmap.len() {
+        bitmap[byte_idx] |= 1 << bit;
+    }
+}
+
+
+fn bitmap_clear_bit(bitmap: &mut [u8], idx: u64) {
+    let byte_idx = (idx / 8) as usize;
+    let bit = (idx % 8) as u8;
+    if byte_idx < bitmap.len() {
+        bitmap[byte_idx] &= !(1 << bit);
+    }
+}
+
+
+fn write_bitmap_block(f: &mut Disk, sb: &SuperBlock, bitmap: &[u8], block_idx: u64) -> std::io::Result<()> {
+    let bs = sb.block_size as usize;
+    let i = (block_idx / 8) as usize / bs;
+    f.seek(SeekFrom::Start((sb.bitmap_start + i as u64) * sb.block_size))?;
+    f.write_all(&bitmap[i * bs..(i + 1) * bs])
+}
+
+
+fn allocate_block(f: &mut Disk, sb: &SuperBlock, bitmap: &mut [u8], hint: u64) -> std::io::Result<Option<u64>> {
+    let hint = hint.clamp(sb.data_start, sb.total_blocks);
+    for block in (hint..sb.total_blocks).chain(sb.data_start..hint) {
+        if !bitmap_get(bitmap, block) {
+            bitmap_set_bit(bitmap, block);
+            write_bitmap_block(f, sb, bitmap, block)?;
+            return Ok(Some(block));
+        }
+    }
+    Ok(None)
+}
+
+
+fn free_block(f: &mut Disk, sb: &SuperBlock, bitmap: &mut [u8], block_idx: u64) -> std::io::Result<()> {
+    bitmap_clear_bit(bitmap, block_idx);
+    write_bitmap_block(f, sb, bitmap, block_idx)
+}
+
+
+fn check_data_block(sb: &SuperBlock, block_idx: u64) -> std::io::Result<()> {
+    if block_idx < sb.data_start || block_idx >= sb.total_blocks {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("block {} is outside the data region ({}..{})", block_idx, sb.data_start, sb.total_blocks),
+        ));
+    }
+    Ok(())
+}
+
+
+fn write_block(f: &mut Disk, sb: &SuperBlock, block_idx: u64, data: &[u8]) -> std::io::Result<()> {
+    check_data_block(sb, block_idx)?;
+    if data.len() as u64 > sb.block_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "data too large for block",
+        ));
+    }
+    let offset = block_idx * sb.block_size;
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(data)?;
+    let pad = (sb.block_size as usize).saturating_sub(data.len());
+    if pad > 0 {
+        let zeros = vec![0u8; pad];
+        f.write_all(&zeros)?;
+    }
+    Ok(())
+}
+
+
+fn read_block(f: &mut Disk, sb: &SuperBlock, block_idx: u64) -> std::io::Result<Vec<u8>> {
+    check_data_block(sb, block_idx)?;
+    let offset = block_idx * sb.block_size;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0u8; sb.block_size as usize];
+    f.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+
+fn chain_len(sb: &SuperBlock, serialized_len: usize) -> usize {
+    serialized_len.div_ceil(sb.payload()).max(1)
+}
+
+
+fn next_in_chain(sb: &SuperBlock, block: &[u8]) -> u64 {
+    u64::from_le_bytes(block[sb.payload()..sb.payload() + 8].try_into().unwrap())
+}
+
+
+fn write_chain(f: &mut Disk, sb: &SuperBlock, blocks: &[u64], serialized: &[u8]) -> std::io::Result<()> {
+    for (i, chunk) in serialized.chunks(sb.payload()).enumerate() {
+        let next = blocks.get(i + 1).copied().unwrap_or(0);
+        let mut buf = chunk.to_vec();
+        buf.resize(sb.payload(), 0);
+        buf.extend_from_slice(&next.to_le_bytes());
+        write_block(f, sb, blocks[i], &buf)?;
+    }
+    Ok(())
+}
+
+
+fn check_geometry(block_count: u64, block_size: u64) -> Result<(), String> {
+    if !block_size.is_multiple_of(8) {
+        return Err(format!("block size {} is not a multiple of 8", block_size));
+    }
+    if block_size < MIN_BLOCK_SIZE {
+        return Err(format!("block size {} cannot hold an entry header, the minimum is {}", block_size, MIN_BLOCK_SIZE));
+    }
+    if block_count <= SuperBlock::new(block_count, block_size).data_start {
+        return Err(format!("{} blocks leave no room for data", block_count));
+    }
+    if block_count.checked_mul(block_size).is_none() {
+        return Err(format!("{} blocks of {} bytes overflow the disk size", block_count, block_size));
+    }
+    Ok(())
+}
+
+
+pub fn initialize_new_disk(path: &str) -> std::io::Result<()> {
+    initialize_new_disk_with_geometry(path, BLOCK_COUNT, BLOCK_SIZE)
+}
+
+
+pub fn initialize_new_disk_with_geometry(path: &str, block_count: u64, block_size: u64) -> std::io::Result<()> {
+    check_geometry(block_count, block_size).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    let mut f = open_disk(path)?;
+    let sb = SuperBlock::new(block_count, block_size);
+    format_disk(&mut f, &sb)?;
+
+    println!("Disco inicializado: '{}' ({} bytes)", path, sb.total_blocks * sb.block_size);
+    Ok(())
+}
+
+
+fn format_disk(f: &mut Disk, sb: &SuperBlock) -> std::io::Result<()> {
+    f.set_len(sb.total_blocks * sb.block_size)?;
+
+    write_superblock(f, sb)?;
+    write_u64(f, INODE_COUNTER_OFFSET, 0)?; 
+    write_u64(f, FORMAT_VERSION_OFFSET, FORMAT_VERSION)?;
+
+    let mut bitmap = vec![0u8; (sb.bitmap_blocks * sb.block_size) as usize];
+    for block in 0..sb.data_start {
+        bitmap_set_bit(&mut bitmap, block);
+    }
+    write_bitmap(f, sb, &bitmap)?;
+
+    f.sync_all()
+}
+
+
+
+pub fn get_default_attrs(file_inode: u64, size: u64, is_folder: bool) -> FileAttr {
+    FileAttr {
+        ino: file_inode,
+        size,
+        blocks: 0,
+        atime: std::time::SystemTime::now(),
+        mtime: std::time::SystemTime::now(),
+        ctime: std::time::SystemTime::now(),
+        crtime: std::time::SystemTime::now(),
+        kind: if is_folder {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        },
+        perm: 0o755,
+        nlink: if is_folder { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+        blksize: 4096,
+    }
+}
+
+
+fn atime_is_stale(attrs: &FileAttr, now: SystemTime) -> bool {
+    attrs.atime <= attrs.mtime || attrs.atime <= attrs.ctime || now.duration_since(attrs.atime).is_ok_and(|age| age >= RELATIME_INTERVAL)
+}
+
+
+// st_blocks is always counted in 512-byte units
+fn reported_attrs(attrs: &FileAttr) -> FileAttr {
+    FileAttr { blocks: attrs.size.div_ceil(512), ..*attrs }
+}
+
+
+pub struct FSEntry {
+    pub inode: u64,
+    pub name:[u8; 25],
+    pub data: Option<Vec<u8>>,
+    pub parent: u64,
+    // hard links besides (parent, name)
+    pub links: Vec<(u64, [u8; 25])>,
+    pub xattrs: HashMap<String, Vec<u8>>,
+    pub children: Vec<(String, u64)>,
+    pub child_index: HashMap<String, u64>,
+    pub attrs: FileAttr,
+}
+
+fn fixed_name(name: &str) -> [u8; 25] {
+    let mut buf = [0u8; 25];
+    let bytes = name.as_bytes();
+    let mut len = bytes.len().min(25);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf[..len].copy_from_slice(&bytes[..len]);
+    buf
+}
+
+fn fixed_name_to_str(buf: &[u8; 25]) -> &str {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(25);
+    match std::str::from_utf8(&buf[..end]) {
+        Ok(name) => name,
+        Err(e) => std::str::from_utf8(&buf[..e.valid_up_to()]).unwrap(),
+    }
+}
+
+fn check_name_len(name: &str) -> Result<(), i32> {
+    if name.len() > MAX_NAME_SIZE {
+        return Err(libc::ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+fn io_errno(e: &std::io::Error) -> i32 {
+    match e.kind() {
+        std::io::ErrorKind::StorageFull => libc::ENOSPC,
+        std::io::ErrorKind::FileTooLarge => libc::EFBIG,
+        _ => libc::EIO,
+    }
+}
+
+fn duplicate_name(name: &str, inode: u64) -> String {
+    let suffix = format!(".dup.{}", inode);
+    let mut end = MAX_NAME_SIZE.saturating_sub(suffix.len()).min(name.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], suffix)
+}
+
+impl FSEntry {
+    pub fn new(file_inode: u64, file_name: String, file_data: Option<Vec<u8>>, parent_inode: u64, file_attrs: &FileAttr) -> Self {
+
+        Self {
+            inode: file_inode,
+            name: fixed_name(&file_name),
+            data: file_data,
+            parent: parent_inode,
+            links: Vec::new(),
+            xattrs: HashMap::new(),
+            children: Vec::new(),
+            child_index: HashMap::new(),
+            attrs: *file_attrs,
+        }
+    }
+
+    // sorted by inode, then name, so readdir order is the same on every mount
+    pub fn add_child(&mut self, name: &str, inode: u64) {
+        let pos = self.children.partition_point(|(n, i)| (*i, n.as_str()) < (inode, name));
+        self.children.insert(pos, (name.to_string(), inode));
+        self.child_index.insert(name.to_string(), inode);
+    }
+
+    pub fn remove_child(&mut self, name: &str, inode: u64) {
+        self.children.retain(|(n, x)| !(*x == inode && n == name));
+        if self.child_index.get(name) == Some(&inode) {
+            self.child_index.remove(name);
+        }
+    }
+
+    pub fn link_count(&self) -> u32 {
+        1 + self.links.len() as u32
+    }
+
+    fn is_primary_link(&self, parent: u64, name: &str) -> bool {
+        self.parent == parent && fixed_name_to_str(&self.name) == name
+    }
+
+    fn extra_link_position(&self, parent: u64, name: &str) -> Option<usize> {
+        self.links.iter().position(|(p, n)| *p == parent && fixed_name_to_str(n) == name)
+    }
+
+    pub fn move_link(&mut self, old_parent: u64, old_name: &str, new_parent: u64, new_name: &str) {
+        if self.is_primary_link(old_parent, old_name) {
+            self.parent = new_parent;
+            self.name = fixed_name(new_name);
+        } else if let Some(i) = self.extra_link_position(old_parent, old_name) {
+            self.links[i] = (new_parent, fixed_name(new_name));
+        }
+    }
+
+    // false when that was the last link
+    pub fn remove_link(&mut self, parent: u64, name: &str) -> bool {
+        if self.is_primary_link(parent, name) {
+            if self.links.is_empty() {
+                return false;
+            }
+            (self.parent, self.name) = self.links.remove(0);
+        } else if let Some(i) = self.extra_link_position(parent, name) {
+            self.links.remove(i);
+        }
+        true
+    }
+}
+
+pub struct OpenFile {
+    pub inode: u64,
+    pub flags: i32,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub stale_mappings: usize,
+    pub orphaned_blocks: usize,
+    pub unmarked_blocks: usize,
+    pub reattached: usize,
+    pub dropped_links: usize,
+    pub remapped: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SetAttrs {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<fuser::TimeOrNow>,
+    pub mtime: Option<fuser::TimeOrNow>,
+    pub ctime: Option<SystemTime>,
+    pub flags: Option<u32>,
+}
+
+pub struct QRFileSystem {
+    pub files: HashMap<u64, FSEntry>,
+    pub inode_block_table: HashMap<u64, Vec<u64>>,
+    pub disk: Disk,
+    pub bitmap: Vec<u8>,
+    pub max_file_blocks: Option<u64>,
+    pub generations: HashMap<u64, u64>,
+    pub max_dir_entries: usize,
+    pub format_version: u64,
+    pub superblock: SuperBlock,
+    pub next_free_hint: u64,
+    pub read_only: bool,
+    pub dirty: HashSet<u64>,
+    pub dirty_since: Option<Instant>,
+    pub flushed: bool,
+    pub inode_counter: u64,
+    pub open_files: HashMap<u64, OpenFile>,
+    pub next_fh: u64,
+    pub open_dirs: HashMap<u64, Vec<(u64, FileType, String)>>,
+}
+
+impl QRFileSystem { //The root inode is always equals one
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        Self::open(path, false)
+    }
+
+    pub fn new_read_only(path: &str) -> std::io::Result<Self> {
+        Self::open(path, true)
+    }
+
+    pub fn in_memory() -> std::io::Result<Self> {
+        let mut disk = Disk::Memory(Cursor::new(Vec::new()));
+        format_disk(&mut disk, &SuperBlock::new(BLOCK_COUNT, BLOCK_SIZE))?;
+        let mut fs = Self::from_disk(disk, false)?;
+        fs.load_fs_from_disk()?;
+        fs.restore_inode_counter()?;
+        Ok(fs)
+    }
+
+    fn open(path: &str, read_only: bool) -> std::io::Result<Self> {
+        let disk = if read_only { open_disk_read_only(path)? } else { open_existing_disk(path)? };
+        Self::from_disk(disk, read_only)
+    }
+
+    fn from_disk(mut disk: Disk, read_only: bool) -> std::io::Result<Self> {
+        let sb = read_superblock(&mut disk, read_only)?;
+        let bm = read_bitmap(&mut disk, &sb)?;
+        let version = read_u64(&mut disk, FORMAT_VERSION_OFFSET)?;
+        if version > FORMAT_VERSION {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("disk uses format v{} but this build only reads up to v{}", version, FORMAT_VERSION)));
+        }
+        Ok(Self {
+            files: HashMap::new(),
+            inode_block_table: HashMap::new(),
+            disk,
+            bitmap: bm,
+            max_file_blocks: None,
+            generations: HashMap::new(),
+            max_dir_entries: DEFAULT_MAX_DIR_ENTRIES,
+            format_version: version,
+            superblock: sb,
+            next_free_hint: sb.data_start,
+            read_only,
+            dirty: HashSet::new(),
+            dirty_since: None,
+            flushed: false,
+            inode_counter: 1,
+            open_files: HashMap::new(),
+            next_fh: 1,
+            open_dirs: HashMap::new(),
+        })
+    }
+
+    pub fn fill_children(&mut self) {
+
+        let mut relations = Vec::new();
+
+        for child in self.files.values() {
+            let inode = child.inode;
+            let parent_inode = child.parent;
+            if parent_inode != 0 {
+                relations.push((parent_inode, fixed_name_to_str(&child.name).to_string(), inode));
+            }
+            for (link_parent, link_name) in &child.links {
+                relations.push((*link_parent, fixed_name_to_str(link_name).to_string(), inode));
+            }
+        }
+
+        relations.sort_by(|a, b| (a.0, a.2, &a.1).cmp(&(b.0, b.2, &b.1)));
+        for (parent_inode, name, inode) in relations {
+            if let Some(parent) = self.files.get_mut(&parent_inode) {
+                parent.add_child(&name, inode);
+            }
+        }
+    }
+
+    pub fn rename_duplicate_siblings(&mut self) -> Vec<u64> {
+        let mut siblings: HashMap<(u64, String), Vec<u64>> = HashMap::new();
+
+        for file in self.files.values() {
+            if file.parent != 0 {
+                let name = fixed_name_to_str(&file.name).to_string();
+                siblings.entry((file.parent, name)).or_default().push(file.inode);
+            }
+        }
+
+        let mut renamed = Vec::new();
+
+        for ((parent_inode, name), mut inodes) in siblings {
+            if inodes.len() < 2 {
+                continue;
+            }
+            inodes.sort();
+
+            for &inode in &inodes[1..] {
+                let new_name = duplicate_name(&name, inode);
+                println!("Duplicate name '{}' in directory {}: inode {} renamed to '{}'", name, parent_inode, inode, new_name);
+
+                if let Some(file) = self.files.get_mut(&inode) {
+                    file.name = fixed_name(&new_name);
+                }
+                let _ = self.write_entry(inode);
+                renamed.push(inode);
+            }
+        }
+
+        renamed
+    }
+
+    pub fn load_fs_from_disk(&mut self) -> std::io::Result<()> {
+        let sb = self.superblock;
+        let bitmap = read_bitmap(&mut self.disk, &sb)?;
+        let mut used_blocks = HashMap::new();
+        for block in sb.data_start..sb.total_blocks {
+            if bitmap_get(&bitmap, block) {
+                used_blocks.insert(block, read_block(&mut self.disk, &sb, block)?);
+            }
+        }
+
+        // v0 is the baseline layout: one entry per block filling all of it, no next pointer
+        let chained = self.format_version >= 2;
+        let payload = if chained { sb.payload() } else { sb.block_size as usize };
+        let next_of = |block: &[u8]| if chained { next_in_chain(&sb, block) } else { 0 };
+
+        let continuations: HashSet<u64> = used_blocks.values().map(|b| next_of(b)).filter(|&n| n != 0).collect();
+
+        for (&head, head_data) in &used_blocks {
+            if continuations.contains(&head) {
+                continue;
+            }
+
+            let mut chain = vec![head];
+            let mut serialized = head_data[..payload].to_vec();
+            let mut next = next_of(head_data);
+            while let Some(block_data) = used_blocks.get(&next) {
+                if chain.contains(&next) {
+                    break;
+                }
+                chain.push(next);
+                serialized.extend_from_slice(&block_data[..payload]);
+                next = next_of(block_data);
+            }
+
+            let file: FSEntry = deserialize_fs_entry(&serialized, self.format_version);
+            self.inode_block_table.insert(file.inode, chain);
+            self.files.insert(file.inode, file);
+        }
+
+        // files unlinked while open whose last handle was never released, nlink is only stored from v2 on
+        if chained {
+            let orphans: Vec<u64> = self.files.values().filter(|f| f.attrs.nlink == 0 && f.attrs.kind != FileType::Directory).map(|f| f.inode).collect();
+            for inode in orphans {
+                self.files.remove(&inode);
+                if self.read_only {
+                    self.inode_block_table.remove(&inode);
+                } else {
+                    self.release_entry_blocks(inode);
+                }
+            }
+        }
+        self.ensure_root()?;
+        self.rename_duplicate_siblings();
+        self.fill_children();
+
+        if self.format_version < FORMAT_VERSION {
+            if self.read_only {
+                self.recount_links();
+            } else {
+                self.migrate_format()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn ensure_root(&mut self) -> std::io::Result<()> {
+        if self.files.contains_key(&1) {
+            return Ok(());
+        }
+        println!("No root directory on disk, creating inode 1");
+
+        let attrs = get_default_attrs(1, 0, true);
+        if self.read_only {
+            self.files.insert(1, FSEntry::new(1, "/".to_string(), None, 0, &attrs));
+            return Ok(());
+        }
+
+        self.push(1, "/".to_string(), None, 0, &attrs)?;
+        if read_u64(&mut self.disk, INODE_COUNTER_OFFSET)? < 1 {
+            write_u64(&mut self.disk, INODE_COUNTER_OFFSET, 1)?;
+        }
+        Ok(())
+    }
+
+    pub fn restore_inode_counter(&mut self) -> std::io::Result<()> {
+        let stored = read_u64(&mut self.disk, INODE_COUNTER_OFFSET)?;
+        let highest = self.files.keys().copied().max().unwrap_or(0);
+
+        if highest > stored {
+            println!("Inode counter on disk is {} but inode {} exists, continuing from {}", stored, highest, highest + 1);
+            if !self.read_only {
+                write_u64(&mut self.disk, INODE_COUNTER_OFFSET, highest)?;
+            }
+        }
+
+        self.inode_counter = stored.max(highest) + 1;
+        Ok(())
+    }
+
+    pub fn next_inode(&mut self) -> u64 {
+        let inode = self.inode_counter;
+        self.inode_counter += 1;
+        self.flushed = false;
+        if !self.read_only {
+            let _ = write_u64(&mut self.disk, INODE_COUNTER_OFFSET, inode);
+        }
+        inode
+    }
+
+    pub fn recount_links(&mut self) {
+        let mut counts = HashMap::new();
+        for file in self.files.values() {
+            let count = if file.attrs.kind == FileType::Directory {
+                2 + file.children.iter().filter(|(_, i)| self.files.get(i).is_some_and(|c| c.attrs.kind == FileType::Directory)).count() as u32
+            } else {
+                file.link_count()
+            };
+            counts.insert(file.inode, count);
+        }
+        for (inode, count) in counts {
+            if let Some(file) = self.files.get_mut(&inode) {
+                file.attrs.nlink = count;
+            }
+        }
+    }
+
+    fn migrate_format(&mut self) -> std::io::Result<()> {
+        println!("Migrating disk from format v{} to v{}", self.format_version.max(1), FORMAT_VERSION);
+        self.recount_links();
+
+        let inodes: Vec<u64> = self.files.keys().copied().collect();
+        for inode in inodes {
+            self.write_entry(inode)?;
+        }
+
+        write_u64(&mut self.disk, FORMAT_VERSION_OFFSET, FORMAT_VERSION)?;
+        self.format_version = FORMAT_VERSION;
+        self.flush()
+    }
+
+    fn adjust_nlink(&mut self, inode: u64, delta: i32) {
+        if let Some(file) = self.files.get_mut(&inode) {
+            file.attrs.nlink = file.attrs.nlink.saturating_add_signed(delta);
+            let _ = self.write_entry(inode);
+        }
+    }
+
+    pub fn find_child(&self, parent_inode: u64, name: &str) -> Option<u64> {
+        self.files.get(&parent_inode)?.child_index.get(name).copied()
+    }
+
+    pub fn lookup_name(&self, parent: u64, name: &OsStr) -> Result<FileAttr, i32> {
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        let inode = self.find_child(parent, name).ok_or(ENOENT)?;
+        Ok(reported_attrs(&self.files[&inode].attrs))
+    }
+
+    pub fn resolve_path(&self, path: &str, follow_symlinks: bool) -> Result<u64, i32> {
+        let mut current = 1;
+        let mut pending: VecDeque<String> = path.split('/').filter(|c| !c.is_empty()).map(str::to_string).collect();
+        let mut hops = 0;
+
+        while let Some(component) = pending.pop_front() {
+            let dir = self.files.get(&current).ok_or(ENOENT)?;
+            if dir.attrs.kind != FileType::Directory {
+                return Err(libc::ENOTDIR);
+            }
+            let next = match component.as_str() {
+                "." => current,
+                ".." if dir.parent != 0 => dir.parent,
+                ".." => current,
+                name => self.find_child(current, name).ok_or(ENOENT)?,
+            };
+
+            let entry = self.files.get(&next).ok_or(ENOENT)?;
+            if !follow_symlinks || entry.attrs.kind != FileType::Symlink {
+                current = next;
+                continue;
+            }
+
+            hops += 1;
+            if hops > MAX_SYMLINK_HOPS {
+                return Err(libc::ELOOP);
+            }
+            let target = String::from_utf8_lossy(entry.data.as_deref().unwrap_or(&[])).into_owned();
+            if target.is_empty() {
+                return Err(ENOENT);
+            }
+            // absolute targets start at the root of this file system, relative ones at the link's directory
+            if target.starts_with('/') {
+                current = 1;
+            }
+            for c in target.split('/').filter(|c| !c.is_empty()).rev() {
+                pending.push_front(c.to_string());
+            }
+        }
+
+        let target = self.files.get(&current).ok_or(ENOENT)?;
+        if path.ends_with('/') && target.attrs.kind != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        Ok(current)
+    }
+
+    pub fn dir_is_full(&self, inode: u64) -> bool {
+        self.files.get(&inode).is_some_and(|dir| dir.children.len() >= self.max_dir_entries)
+    }
+
+    pub fn generation(&self, inode: u64) -> u64 {
+        self.generations.get(&inode).copied().unwrap_or(0)
+    }
+
+    pub fn write_data(&mut self, inode: u64, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        let file = match self.files.get(&inode) {
+            Some(f) => f,
+            None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such inode")),
+        };
+        let current_len = file.data.as_ref().map_or(0, |d| d.len());
+        let required_size = offset.checked_add(data.len()).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::FileTooLarge, "offset past the largest file size"))?;
+        self.check_fits(inode, required_size.max(current_len))?;
+
+        let file = self.files.get_mut(&inode).unwrap();
+        let previous = (file.data.clone(), file.attrs.size);
+
+        let buffer = file.data.get_or_insert_with(Vec::new);
+        if buffer.len() < required_size {
+            buffer.resize(required_size, 0);
+        }
+
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+        file.attrs.size = buffer.len() as u64;
+        file.attrs.mtime = SystemTime::now();
+        file.attrs.ctime = file.attrs.mtime;
+
+        if let Err(e) = self.write_entry_deferred(inode) {
+            if let Some(file) = self.files.get_mut(&inode) {
+                (file.data, file.attrs.size) = previous;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // run before the data is resized in memory, so a size the disk could never hold is not allocated first
+    fn check_fits(&self, inode: u64, data_len: usize) -> std::io::Result<()> {
+        let Some(file) = self.files.get(&inode) else {
+            return Ok(());
+        };
+        let header_len = serialized_len(file) - file.data.as_ref().map_or(0, |d| d.len());
+        let needed = chain_len(&self.superblock, header_len.saturating_add(data_len)) as u64;
+
+        if let Some(cap) = self.max_file_blocks && needed > cap {
+            return Err(std::io::Error::new(std::io::ErrorKind::FileTooLarge, "the file would go over --blocks-per-file"));
+        }
+        let owned = self.inode_block_table.get(&inode).map_or(0, |b| b.len() as u64);
+        if needed > owned && needed - owned > self.free_blocks() {
+            return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no free blocks available"));
+        }
+        Ok(())
+    }
+
+    pub fn make_entry(&mut self, parent: u64, name: &str, data: Option<Vec<u8>>, template: &FileAttr) -> Result<FileAttr, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        check_name_len(name)?;
+        if self.find_child(parent, name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        if self.dir_is_full(parent) {
+            return Err(libc::ENOSPC);
+        }
+
+        let inode = self.next_inode();
+        let attrs = FileAttr { ino: inode, ..*template };
+        self.push(inode, name.to_string(), data, parent, &attrs).map_err(|e| io_errno(&e))
+    }
+
+    pub fn make_dir(&mut self, parent: u64, name: &str, mode: u32, umask: u32, uid: u32, gid: u32) -> Result<FileAttr, i32> {
+        let mut attrs = get_default_attrs(0, 0, true);
+        attrs.perm = (mode & !umask & 0o7777) as u16;
+        (attrs.uid, attrs.gid) = (uid, gid);
+        self.make_entry(parent, name, None, &attrs)
+    }
+
+    pub fn create_file(&mut self, parent: u64, name: &str, mode: u32, umask: u32, uid: u32, gid: u32) -> Result<FileAttr, i32> {
+        let mut attrs = get_default_attrs(0, 0, false);
+        attrs.perm = (mode & !umask & 0o7777) as u16;
+        (attrs.uid, attrs.gid) = (uid, gid);
+        self.make_entry(parent, name, None, &attrs)
+    }
+
+    pub fn set_xattr(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        let xattr_name = match name.to_str() {
+            Some(n) if !n.is_empty() => n.to_string(),
+            _ => return Err(libc::EINVAL),
+        };
+        if xattr_name.len() > XATTR_NAME_MAX {
+            return Err(libc::ERANGE);
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(libc::E2BIG);
+        }
+        // only macOS resource forks use position
+        if position != 0 {
+            return Err(libc::EINVAL);
+        }
+
+        let file = self.files.get_mut(&ino).ok_or(ENOENT)?;
+        let exists = file.xattrs.contains_key(&xattr_name);
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(libc::EEXIST);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(libc::ENODATA);
+        }
+
+        let previous = file.xattrs.insert(xattr_name.clone(), value.to_vec());
+        file.attrs.ctime = SystemTime::now();
+
+        if let Err(e) = self.write_entry(ino) {
+            if let Some(file) = self.files.get_mut(&ino) {
+                match previous {
+                    Some(v) => file.xattrs.insert(xattr_name, v),
+                    None => file.xattrs.remove(&xattr_name),
+                };
+            }
+            return Err(io_errno(&e));
+        }
+        Ok(())
+    }
+
+    pub fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<&[u8], i32> {
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+        name.to_str().and_then(|n| file.xattrs.get(n)).map(|v| v.as_slice()).ok_or(libc::ENODATA)
+    }
+
+    pub fn list_xattrs(&self, ino: u64) -> Result<Vec<u8>, i32> {
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+        let mut names = Vec::new();
+        for xattr_name in file.xattrs.keys() {
+            names.extend_from_slice(xattr_name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    pub fn remove_xattr(&mut self, ino: u64, name: &OsStr) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        let file = self.files.get_mut(&ino).ok_or(ENOENT)?;
+
+        let xattr_name = name.to_string_lossy().to_string();
+        let previous = file.xattrs.remove(&xattr_name).ok_or(libc::ENODATA)?;
+        file.attrs.ctime = SystemTime::now();
+
+        if let Err(e) = self.write_entry(ino) {
+            if let Some(file) = self.files.get_mut(&ino) {
+                file.xattrs.insert(xattr_name, previous);
+            }
+            return Err(io_errno(&e));
+        }
+        Ok(())
+    }
+
+    pub fn make_node(&mut self, parent: u64, name: &str, mode: u32, rdev: u32, uid: u32, gid: u32) -> Result<FileAttr, i32> {
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFREG | 0 => FileType::RegularFile,
+            libc::S_IFIFO => FileType::NamedPipe,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFSOCK => FileType::Socket,
+            // directories go through mkdir, symlinks through symlink
+            _ => return Err(libc::EINVAL),
+        };
+
+        let mut attrs = get_default_attrs(0, 0, false);
+        attrs.kind = kind;
+        attrs.perm = (mode & 0o7777) as u16;
+        attrs.rdev = rdev;
+        (attrs.uid, attrs.gid) = (uid, gid);
+        self.make_entry(parent, name, None, &attrs)
+    }
+
+    pub fn make_symlink(&mut self, parent: u64, name: &str, target: &[u8], uid: u32, gid: u32) -> Result<FileAttr, i32> {
+        let mut attrs = get_default_attrs(0, target.len() as u64, false);
+        attrs.kind = FileType::Symlink;
+        attrs.perm = 0o777;
+        (attrs.uid, attrs.gid) = (uid, gid);
+        self.make_entry(parent, name, Some(target.to_vec()), &attrs)
+    }
+
+    pub fn read_link(&self, ino: u64) -> Result<&[u8], i32> {
+        match self.files.get(&ino) {
+            Some(f) if f.attrs.kind == FileType::Symlink => Ok(f.data.as_deref().unwrap_or(&[])),
+            Some(_) => Err(libc::EINVAL),
+            None => Err(ENOENT),
+        }
+    }
+
+    pub fn check_access(&self, ino: u64, mask: i32) -> Result<(), i32> {
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+        let perm = file.attrs.perm;
+
+        const R_OK: i32 = 4;
+        const W_OK: i32 = 2;
+        const X_OK: i32 = 1;
+
+        if (mask & R_OK) != 0 && (perm & 0o444 == 0) {
+            return Err(libc::EACCES);
+        }
+
+        if (mask & W_OK) != 0 && (perm & 0o222 == 0) {
+            return Err(libc::EACCES);
+        }
+
+        if (mask & W_OK) != 0 && self.read_only {
+            return Err(libc::EROFS);
+        }
+
+        if (mask & X_OK) != 0 && (perm & 0o111 == 0) {
+            return Err(libc::EACCES);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_attributes(&mut self, ino: u64, changes: SetAttrs) -> Result<FileAttr, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+
+        if !self.inode_block_table.contains_key(&ino) {
+            println!("inode {} has no block mapping", ino);
+            return Err(libc::EIO);
+        }
+
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+
+        let only_flags = changes.mode.is_none() && changes.uid.is_none() && changes.gid.is_none() && changes.size.is_none() && changes.atime.is_none() && changes.mtime.is_none();
+        // clearing the flag is the one change an immutable entry accepts
+        if file.attrs.flags & FS_IMMUTABLE_FL != 0 && !only_flags {
+            return Err(libc::EPERM);
+        }
+
+        if let Some(sz) = changes.size {
+            if file.attrs.kind == FileType::Directory {
+                return Err(libc::EISDIR);
+            }
+            self.check_fits(ino, sz as usize).map_err(|e| io_errno(&e))?;
+        }
+
+        let file = self.files.get_mut(&ino).unwrap();
+        let previous = (file.data.clone(), file.attrs);
+
+        if let Some(m) = changes.mode {
+            file.attrs.perm = (m & 0o7777) as u16;
+        }
+
+        if let Some(u) = changes.uid {
+            file.attrs.uid = u;
+        }
+
+        if let Some(g) = changes.gid {
+            file.attrs.gid = g;
+        }
+
+        if let Some(t) = changes.atime {
+            file.attrs.atime = time_or_now(t);
+        }
+
+        if let Some(t) = changes.mtime {
+            file.attrs.mtime = time_or_now(t);
+        }
+
+        if let Some(f) = changes.flags {
+            file.attrs.flags = f;
+        }
+
+        if let Some(sz) = changes.size {
+            file.data.get_or_insert_with(Vec::new).resize(sz as usize, 0);
+            file.attrs.size = sz;
+            if changes.mtime.is_none() {
+                file.attrs.mtime = SystemTime::now();
+            }
+        }
+
+        file.attrs.ctime = changes.ctime.unwrap_or_else(SystemTime::now);
+
+        if let Err(e) = self.write_entry(ino) {
+            if let Some(file) = self.files.get_mut(&ino) {
+                (file.data, file.attrs) = previous;
+            }
+            return Err(io_errno(&e));
+        }
+
+        Ok(self.files[&ino].attrs)
+    }
+
+    pub fn read_data(&mut self, inode: u64, offset: usize, size: usize) -> Result<Vec<u8>, i32> {
+        let file = self.files.get_mut(&inode).ok_or(ENOENT)?;
+        if file.attrs.kind == FileType::Directory {
+            return Err(ENOENT);
+        }
+
+        let bytes = match &file.data {
+            Some(data) => {
+                let start = offset.min(data.len());
+                let end = start.saturating_add(size).min(data.len());
+                data[start..end].to_vec()
+            },
+            None => Vec::new(),
+        };
+
+        let now = SystemTime::now();
+        if !self.read_only && atime_is_stale(&file.attrs, now) {
+            file.attrs.atime = now;
+            let _ = self.write_entry_deferred(inode);
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn write_handle(&mut self, inode: u64, fh: u64, offset: usize, data: &[u8]) -> Result<u32, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        if !self.inode_block_table.contains_key(&inode) {
+            println!("inode {} has no block mapping", inode);
+            return Err(libc::EIO);
+        }
+
+        let file = self.files.get(&inode).ok_or(ENOENT)?;
+        if file.attrs.kind == FileType::Directory {
+            return Err(ENOENT);
+        }
+        if file.attrs.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(libc::EPERM);
+        }
+
+        let handle = self.open_files.get(&fh);
+        if handle.is_some_and(|h| h.flags & libc::O_ACCMODE == libc::O_RDONLY) {
+            return Err(libc::EBADF);
+        }
+
+        let current_len = file.data.as_ref().map_or(0, |d| d.len());
+        let append = handle.is_some_and(|h| h.flags & libc::O_APPEND != 0);
+        // the kernel's idea of the size can be stale when another handle just wrote
+        let offset = if append { current_len } else { offset };
+
+        self.write_data(inode, offset, data).map_err(|e| io_errno(&e))?;
+        Ok(data.len() as u32)
+    }
+
+    pub fn release_handle(&mut self, inode: u64, fh: u64) -> Result<(), i32> {
+        self.open_files.remove(&fh);
+        let unlinked = self.files.get(&inode).is_some_and(|f| f.attrs.nlink == 0 && f.attrs.kind != FileType::Directory);
+        if unlinked && !self.open_files.values().any(|f| f.inode == inode) {
+            self.release_entry_blocks(inode);
+            self.files.remove(&inode);
+            return Ok(());
+        }
+        if !self.read_only && self.dirty.contains(&inode) {
+            self.flush_entry(inode).map_err(|e| io_errno(&e))?;
+        }
+        Ok(())
+    }
+
+    pub fn seek(&self, inode: u64, offset: i64, whence: i32) -> Result<i64, i32> {
+        let size = self.files.get(&inode).ok_or(ENOENT)?.attrs.size as i64;
+        if offset < 0 || offset >= size {
+            return Err(libc::ENXIO);
+        }
+
+        // holes are stored as zeros, so the only hole is the end of the file
+        match whence {
+            libc::SEEK_DATA => Ok(offset),
+            libc::SEEK_HOLE => Ok(size),
+            _ => Err(libc::EINVAL),
+        }
+    }
+
+    pub fn read_dir(&mut self, inode: u64, fh: u64, offset: i64, mut add: impl FnMut(u64, i64, FileType, &str) -> bool) -> Result<(), i32> {
+        if offset == 0 {
+            self.rewind_dir(inode, fh);
+        }
+
+        let current;
+        let entries = match self.open_dirs.get(&fh) {
+            Some(snapshot) => snapshot,
+            None => {
+                current = self.dir_entries(inode).ok_or(ENOENT)?;
+                &current
+            }
+        };
+
+        for (i, (entry_inode, kind, name)) in entries.iter().enumerate().skip(offset.max(0) as usize) {
+            if add(*entry_inode, i as i64 + 1, *kind, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        let sb = self.superblock;
+        let used_blocks = (0..sb.total_blocks).filter(|&b| bitmap_get(&self.bitmap, b)).count() as u64;
+        sb.total_blocks - used_blocks
+    }
+
+    pub fn allocate_fh(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        fh
+    }
+
+    pub fn copy_range(&mut self, ino_in: u64, offset_in: usize, ino_out: u64, fh_out: u64, offset_out: usize, len: u64) -> Result<u32, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let (source, target) = match (self.files.get(&ino_in), self.files.get(&ino_out)) {
+            (Some(s), Some(t)) => (s, t),
+            _ => return Err(ENOENT),
+        };
+        if source.attrs.kind == FileType::Directory || target.attrs.kind == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        if target.attrs.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(libc::EPERM);
+        }
+        if self.open_files.get(&fh_out).is_some_and(|h| h.flags & libc::O_ACCMODE == libc::O_RDONLY) {
+            return Err(libc::EBADF);
+        }
+
+        let source_data = source.data.as_deref().unwrap_or(&[]);
+        let start = offset_in.min(source_data.len());
+        let end = start.saturating_add((len as usize).min(u32::MAX as usize)).min(source_data.len());
+        // copied out first, so an overlapping range of the same file still reads the old bytes
+        let chunk = source_data[start..end].to_vec();
+        if chunk.is_empty() {
+            return Ok(0);
+        }
+
+        self.write_data(ino_out, offset_out, &chunk).map_err(|e| io_errno(&e))?;
+        Ok(chunk.len() as u32)
+    }
+
+    pub fn allocate_range(&mut self, ino: u64, offset: i64, length: i64, mode: i32) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        if offset < 0 || length <= 0 {
+            return Err(libc::EINVAL);
+        }
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+        if file.attrs.kind == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        if file.attrs.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(libc::EPERM);
+        }
+
+        // blocks always follow the size, there is nothing to reserve past it
+        let end = (offset as u64).saturating_add(length as u64);
+        if end <= file.attrs.size || mode & libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Ok(());
+        }
+
+        self.write_data(ino, end as usize, &[]).map_err(|e| io_errno(&e))
+    }
+
+    pub fn open_file(&mut self, ino: u64, flags: i32) -> Result<u64, i32> {
+        let file = self.files.get(&ino).ok_or(ENOENT)?;
+
+        let write_mode = flags & (libc::O_WRONLY | libc::O_RDWR) != 0;
+        if file.attrs.kind == FileType::Directory && write_mode {
+            return Err(libc::EISDIR);
+        }
+        if self.read_only && (write_mode || flags & libc::O_TRUNC != 0) {
+            return Err(libc::EROFS);
+        }
+        if file.attrs.flags & FS_IMMUTABLE_FL != 0 && (write_mode || flags & libc::O_TRUNC != 0) {
+            return Err(libc::EPERM);
+        }
+
+        Ok(self.open_handle(ino, flags))
+    }
+
+    pub fn open_handle(&mut self, inode: u64, flags: i32) -> u64 {
+        let fh = self.allocate_fh();
+        self.open_files.insert(fh, OpenFile { inode, flags });
+        fh
+    }
+
+    // blocks under each directory directly in the root, an inode reached twice is counted once
+    pub fn dir_usage_map(&self) -> HashMap<u64, u64> {
+        let mut usage = HashMap::new();
+        let Some(root) = self.files.get(&1) else {
+            return usage;
+        };
+
+        for (_, top) in &root.children {
+            if !self.files.get(top).is_some_and(|d| d.attrs.kind == FileType::Directory) {
+                continue;
+            }
+            let mut seen = HashSet::new();
+            let mut pending = vec![*top];
+            let mut blocks = 0;
+            while let Some(inode) = pending.pop() {
+                if !seen.insert(inode) {
+                    continue;
+                }
+                blocks += self.inode_block_table.get(&inode).map_or(0, |b| b.len() as u64);
+                if let Some(entry) = self.files.get(&inode) {
+                    pending.extend(entry.children.iter().map(|(_, child)| *child));
+                }
+            }
+            usage.insert(*top, blocks);
+        }
+        usage
+    }
+
+    pub fn dir_entries(&self, inode: u64) -> Option<Vec<(u64, FileType, String)>> {
+        let dir = self.files.get(&inode).filter(|d| d.attrs.kind == FileType::Directory)?;
+        let parent = if dir.parent != 0 { dir.parent } else { inode };
+
+        let mut entries = vec![(inode, FileType::Directory, ".".to_string()), (parent, FileType::Directory, "..".to_string())];
+        for (name, child_inode) in &dir.children {
+            if let Some(child) = self.files.get(child_inode) {
+                entries.push((child.inode, child.attrs.kind, name.clone()));
+            }
+        }
+        Some(entries)
+    }
+
+    pub fn open_dir(&mut self, inode: u64) -> Option<u64> {
+        let entries = self.dir_entries(inode)?;
+        let fh = self.allocate_fh();
+        self.open_dirs.insert(fh, entries);
+        Some(fh)
+    }
+
+    pub fn rewind_dir(&mut self, inode: u64, fh: u64) {
+        if self.open_dirs.contains_key(&fh) && let Some(entries) = self.dir_entries(inode) {
+            self.open_dirs.insert(fh, entries);
+        }
+    }
+
+    pub fn is_immutable(&self, inode: u64) -> bool {
+        self.files.get(&inode).is_some_and(|f| f.attrs.flags & FS_IMMUTABLE_FL != 0)
+    }
+
+    pub fn defragment_inode_blocks(&mut self) -> std::io::Result<()> {
+        self.flushed = false;
+        let mut roots: Vec<u64> = self.files.values().filter(|f| f.parent == 0).map(|f| f.inode).collect();
+        roots.sort();
+
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<u64> = roots.into_iter().collect();
+
+        while let Some(inode) = queue.pop_front() {
+            if !visited.insert(inode) {
+                continue;
+            }
+            order.push(inode);
+            if let Some(file) = self.files.get(&inode) {
+                queue.extend(file.children.iter().map(|(_, i)| *i));
+            }
+        }
+
+        let mut unreachable: Vec<u64> = self.files.keys().filter(|i| !visited.contains(*i)).copied().collect();
+        unreachable.sort();
+        order.extend(unreachable);
+
+        let sb = self.superblock;
+        let mut owners: HashMap<u64, u64> = HashMap::new();
+        for (&inode, blocks) in &self.inode_block_table {
+            for &block_idx in blocks {
+                owners.insert(block_idx, inode);
+            }
+        }
+
+        let mut next_block = sb.data_start;
+        for &inode in &order {
+            let len = chain_len(&sb, serialized_len(&self.files[&inode])) as u64;
+            let target: Vec<u64> = (next_block..next_block + len).collect();
+            if next_block + len > sb.total_blocks {
+                return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "entries do not fit on the disk"));
+            }
+
+            if self.inode_block_table.get(&inode) != Some(&target) {
+                let mut in_the_way: Vec<u64> = target.iter().filter_map(|b| owners.get(b)).copied().collect();
+                in_the_way.sort_unstable();
+                in_the_way.dedup();
+                for other in in_the_way {
+                    let other_len = self.inode_block_table[&other].len();
+                    let spare: Vec<u64> = (next_block + len..sb.total_blocks).filter(|&b| !bitmap_get(&self.bitmap, b)).take(other_len).collect();
+                    if spare.len() < other_len {
+                        return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "not enough free blocks to move entries out of the way"));
+                    }
+                    self.move_chain(other, &spare, &mut owners)?;
+                }
+                self.move_chain(inode, &target, &mut owners)?;
+            }
+            next_block += len;
+        }
+
+        self.next_free_hint = next_block;
+        Ok(())
+    }
+
+    fn move_chain(&mut self, inode: u64, blocks: &[u64], owners: &mut HashMap<u64, u64>) -> std::io::Result<()> {
+        let sb = self.superblock;
+        let data = serialize_fs_entry_to_disk(&self.files[&inode]);
+        write_chain(&mut self.disk, &sb, blocks, &data)?;
+        self.disk.sync_all()?;
+
+        let old = self.inode_block_table.insert(inode, blocks.to_vec()).unwrap_or_default();
+        for &block_idx in &old {
+            bitmap_clear_bit(&mut self.bitmap, block_idx);
+            owners.remove(&block_idx);
+        }
+        for &block_idx in blocks {
+            bitmap_set_bit(&mut self.bitmap, block_idx);
+            owners.insert(block_idx, inode);
+        }
+        // the one write that switches the entry over, before it only the old chain is marked and after it only the new one
+        write_bitmap(&mut self.disk, &sb, &self.bitmap)?;
+        self.dirty.remove(&inode);
+        self.disk.sync_all()
+    }
+
+    pub fn fsck(&mut self) -> std::io::Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let sb = self.superblock;
+        self.flushed = false;
+
+        let stale: Vec<u64> = self.inode_block_table.keys().filter(|i| !self.files.contains_key(*i)).copied().collect();
+        for inode in stale {
+            println!("fsck: inode {} has blocks but no entry, releasing them", inode);
+            self.inode_block_table.remove(&inode);
+            self.dirty.remove(&inode);
+            report.stale_mappings += 1;
+        }
+
+        let used: HashSet<u64> = self.inode_block_table.values().flatten().copied().collect();
+        for block in 0..sb.total_blocks {
+            let in_use = block < sb.data_start || used.contains(&block);
+            if bitmap_get(&self.bitmap, block) && !in_use {
+                bitmap_clear_bit(&mut self.bitmap, block);
+                report.orphaned_blocks += 1;
+            } else if !bitmap_get(&self.bitmap, block) && in_use {
+                bitmap_set_bit(&mut self.bitmap, block);
+                report.unmarked_blocks += 1;
+            }
+        }
+        // before anything is allocated below
+        write_bitmap(&mut self.disk, &sb, &self.bitmap)?;
+        self.next_free_hint = sb.data_start;
+
+        let is_dir = |files: &HashMap<u64, FSEntry>, inode: u64| files.get(&inode).is_some_and(|d| d.attrs.kind == FileType::Directory);
+        let mut rewrite = Vec::new();
+        let mut inodes: Vec<u64> = self.files.keys().copied().collect();
+        inodes.sort();
+
+        for &inode in &inodes {
+            let file = &self.files[&inode];
+            let links_before = file.links.len();
+            let links: Vec<(u64, [u8; 25])> = file.links.iter().filter(|(p, _)| is_dir(&self.files, *p)).copied().collect();
+            let orphaned = inode != 1 && !is_dir(&self.files, file.parent);
+            if !orphaned && links.len() == links_before {
+                continue;
+            }
+            rewrite.push(inode);
+
+            let file = self.files.get_mut(&inode).unwrap();
+            report.dropped_links += links_before - links.len();
+            file.links = links;
+            if !orphaned {
+                file.attrs.nlink = file.link_count();
+                continue;
+            }
+
+            let name = fixed_name_to_str(&file.name).to_string();
+            let new_name = if self.files[&1].child_index.contains_key(&name) { duplicate_name(&name, inode) } else { name };
+            println!("fsck: inode {} lost its parent directory, moving it to /{}", inode, new_name);
+            let file = self.files.get_mut(&inode).unwrap();
+            file.parent = 1;
+            file.name = fixed_name(&new_name);
+            let moved_dir = file.attrs.kind == FileType::Directory;
+            if !moved_dir {
+                file.attrs.nlink = file.link_count();
+            }
+            self.files.get_mut(&1).unwrap().add_child(fixed_name_to_str(&fixed_name(&new_name)), inode);
+            if moved_dir {
+                self.adjust_nlink(1, 1);
+            }
+            report.reattached += 1;
+        }
+
+        for &inode in &inodes {
+            if !self.inode_block_table.contains_key(&inode) {
+                println!("fsck: inode {} has no block mapping, writing it to fresh blocks", inode);
+                report.remapped += 1;
+                rewrite.push(inode);
+            }
+        }
+        for inode in rewrite {
+            self.write_entry(inode)?;
+        }
+
+        self.flush()?;
+        Ok(report)
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.write_dirty()?;
+        write_bitmap(&mut self.disk, &self.superblock, &self.bitmap)?;
+        self.disk.sync_all()?;
+        self.flushed = true;
+        Ok(())
+    }
+
+    pub fn flush_entry(&mut self, inode: u64) -> std::io::Result<()> {
+        if self.dirty.contains(&inode) {
+            self.write_entry(inode)?;
+        }
+        write_bitmap(&mut self.disk, &self.superblock, &self.bitmap)?;
+        self.disk.sync_all()
+    }
+
+    pub fn write_dirty(&mut self) -> std::io::Result<()> {
+        let dirty: Vec<u64> = self.dirty.iter().copied().collect();
+        for inode in dirty {
+            self.write_entry(inode)?;
+        }
+        self.dirty_since = None;
+        Ok(())
+    }
+
+    pub fn write_dirty_if_due(&mut self) -> std::io::Result<()> {
+        if self.dirty_since.is_some_and(|since| since.elapsed() >= DIRTY_INTERVAL) {
+            self.write_dirty()?;
+        }
+        Ok(())
+    }
+
+    // only a change that keeps the chain length waits, so allocation errors still surface right away
+    pub fn write_entry_deferred(&mut self, inode: u64) -> std::io::Result<()> {
+        let needed = match self.files.get(&inode) {
+            Some(f) => chain_len(&self.superblock, serialized_len(f)),
+            None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such inode")),
+        };
+        if self.inode_block_table.get(&inode).map(|b| b.len()) != Some(needed) {
+            return self.write_entry(inode);
+        }
+
+        if self.dirty.is_empty() {
+            self.dirty_since = Some(Instant::now());
+        }
+        self.dirty.insert(inode);
+        self.flushed = false;
+        if self.dirty.len() >= DIRTY_LIMIT {
+            return self.write_dirty();
+        }
+        self.write_dirty_if_due()
+    }
+
+    fn allocate(&mut self) -> std::io::Result<Option<u64>> {
+        let block = allocate_block(&mut self.disk, &self.superblock, &mut self.bitmap, self.next_free_hint)?;
+        if let Some(block_idx) = block {
+            self.next_free_hint = block_idx + 1;
+        }
+        Ok(block)
+    }
+
+    fn release_block(&mut self, block_idx: u64) -> std::io::Result<()> {
+        self.flushed = false;
+        free_block(&mut self.disk, &self.superblock, &mut self.bitmap, block_idx)?;
+        self.next_free_hint = self.next_free_hint.min(block_idx);
+        Ok(())
+    }
+
+    pub fn write_entry(&mut self, inode: u64) -> std::io::Result<()> {
+        let serialized = match self.files.get(&inode) {
+            Some(f) => serialize_fs_entry_to_disk(f),
+            None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such inode")),
+        };
+        let sb = self.superblock;
+        let needed = chain_len(&sb, serialized.len());
+        let mut blocks = self.inode_block_table.get(&inode).cloned().unwrap_or_default();
+        let already_owned = blocks.len();
+        self.flushed = false;
+
+        while blocks.len() < needed {
+            match self.allocate()? {
+                Some(block_idx) => blocks.push(block_idx),
+                None => {
+                    for &block_idx in &blocks[already_owned..] {
+                        self.release_block(block_idx)?;
+                    }
+                    return Err(std::io::Error::new(std::io::ErrorKind::StorageFull, "no free blocks available"));
+                }
+            }
+        }
+
+        for block_idx in blocks.split_off(needed) {
+            self.release_block(block_idx)?;
+        }
+
+        write_chain(&mut self.disk, &sb, &blocks, &serialized)?;
+        self.inode_block_table.insert(inode, blocks);
+        self.dirty.remove(&inode);
+        Ok(())
+    }
+
+    pub fn release_entry_blocks(&mut self, inode: u64) {
+        self.dirty.remove(&inode);
+        if let Some(blocks) = self.inode_block_table.remove(&inode) {
+            for block_idx in blocks {
+                let _ = self.release_block(block_idx);
+            }
+        }
+    }
+
+    pub fn push(&mut self, inode: u64, file_name: String, data: Option<Vec<u8>>, parent_inode: u64, file_attrs: &FileAttr) -> std::io::Result<FileAttr> {
+        let file: FSEntry = FSEntry::new(inode, file_name.clone(), data, parent_inode, file_attrs);
+        self.files.insert(inode, file);
+
+        if let Err(e) = self.write_entry(inode) {
+            self.files.remove(&inode);
+            self.release_entry_blocks(inode);
+            return Err(e);
+        }
+        self.generations.entry(inode).and_modify(|g| *g += 1).or_insert(0);
+
+        if let Some(parent) = self.files.get_mut(&parent_inode) {
+            parent.add_child(fixed_name_to_str(&fixed_name(&file_name)), inode);
+        }
+        if file_attrs.kind == FileType::Directory {
+            self.adjust_nlink(parent_inode, 1);
+        }
+
+        Ok(*file_attrs)
+    }
+
+    pub fn link_entry(&mut self, ino: u64, newparent: u64, new_name: &str) -> Result<FileAttr, i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        check_name_len(new_name)?;
+
+        match self.files.get(&ino) {
+            Some(f) if f.attrs.kind == FileType::Directory || f.attrs.flags & FS_IMMUTABLE_FL != 0 => return Err(libc::EPERM),
+            Some(_) => {},
+            None => return Err(ENOENT),
+        }
+        match self.files.get(&newparent) {
+            Some(p) if p.attrs.kind != FileType::Directory => return Err(libc::ENOTDIR),
+            Some(_) => {},
+            None => return Err(ENOENT),
+        }
+        if self.find_child(newparent, new_name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        if self.dir_is_full(newparent) {
+            return Err(libc::ENOSPC);
+        }
+
+        let file = self.files.get_mut(&ino).unwrap();
+        file.links.push((newparent, fixed_name(new_name)));
+        file.attrs.nlink = file.link_count();
+
+        if let Err(e) = self.write_entry(ino) {
+            let file = self.files.get_mut(&ino).unwrap();
+            file.links.pop();
+            file.attrs.nlink = file.link_count();
+            return Err(io_errno(&e));
+        }
+
+        if let Some(parent_dir) = self.files.get_mut(&newparent) {
+            parent_dir.add_child(new_name, ino);
+        }
+
+        Ok(reported_attrs(&self.files[&ino].attrs))
+    }
+
+    pub fn unlink_name(&mut self, parent: u64, name: &str) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let target_inode = self.find_child(parent, name).ok_or(ENOENT)?;
+        if self.files[&target_inode].attrs.kind == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        if self.is_immutable(target_inode) {
+            return Err(libc::EPERM);
+        }
+
+        self.unlink_entry(parent, name, target_inode);
+        Ok(())
+    }
+
+    pub fn unlink_entry(&mut self, parent: u64, name: &str, inode: u64) {
+        let open = self.open_files.values().any(|f| f.inode == inode);
+        if let Some(file) = self.files.get_mut(&inode) {
+            if file.remove_link(parent, name) {
+                file.attrs.nlink = file.link_count();
+                let _ = self.write_entry(inode);
+            } else if open {
+                // nlink 0 keeps it out of every directory, release_handle frees it once the last handle closes
+                file.attrs.nlink = 0;
+                let _ = self.write_entry(inode);
+            } else {
+                self.release_entry_blocks(inode);
+                self.files.remove(&inode);
+            }
+        }
+
+        if let Some(parent_dir) = self.files.get_mut(&parent) {
+            parent_dir.remove_child(name, inode);
+        }
+    }
+
+    pub fn remove_dir(&mut self, parent: u64, name: &str) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+
+        let target_inode = self.find_child(parent, name).ok_or(ENOENT)?;
+        if let Some(child) = self.files.get(&target_inode) {
+            if child.attrs.kind != FileType::Directory {
+                return Err(libc::ENOTDIR);
+            }
+            if self.is_immutable(target_inode) {
+                return Err(libc::EPERM);
+            }
+            if !child.children.is_empty() {
+                return Err(libc::ENOTEMPTY);
+            }
+        }
+
+        self.remove_subtree(target_inode);
+        Ok(())
+    }
+
+    pub fn remove_subtree(&mut self, inode: u64) {
+        let (parent, name, is_dir) = match self.files.get(&inode) {
+            Some(f) => (f.parent, fixed_name_to_str(&f.name).to_string(), f.attrs.kind == FileType::Directory),
+            None => return,
+        };
+        if !is_dir {
+            self.unlink_entry(parent, &name, inode);
+            return;
+        }
+
+        if let Some(parent_file) = self.files.get_mut(&parent) {
+            parent_file.remove_child(&name, inode);
+        }
+        self.adjust_nlink(parent, -1);
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![inode];
+        while let Some(dir_inode) = stack.pop() {
+            if !visited.insert(dir_inode) {
+                continue;
+            }
+            let children = match self.files.get(&dir_inode) {
+                Some(dir) => dir.children.clone(),
+                None => continue,
+            };
+
+            for (child_name, child_inode) in children {
+                if self.files.get(&child_inode).is_some_and(|c| c.attrs.kind == FileType::Directory) {
+                    stack.push(child_inode);
+                } else {
+                    self.unlink_entry(dir_inode, &child_name, child_inode);
+                }
+            }
+
+            self.release_entry_blocks(dir_inode);
+            self.files.remove(&dir_inode);
+        }
+    }
+
+    pub fn rename_entry(&mut self, parent: u64, old_name: &str, newparent: u64, new_name: &str, flags: u32) -> Result<(), i32> {
+        if self.read_only {
+            return Err(libc::EROFS);
+        }
+        // RENAME_EXCHANGE would otherwise fall through to replacing the target
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(libc::EINVAL);
+        }
+        check_name_len(new_name)?;
+        let source = self.find_child(parent, old_name).ok_or(ENOENT)?;
+        if self.is_immutable(source) {
+            return Err(libc::EPERM);
+        }
+        let mut ancestor = newparent;
+        let mut seen = HashSet::new();
+        while ancestor != 0 && seen.insert(ancestor) {
+            if ancestor == source {
+                return Err(libc::EINVAL);
+            }
+            ancestor = self.files.get(&ancestor).map_or(0, |d| d.parent);
+        }
+
+        match self.find_child(newparent, new_name) {
+            Some(_) if flags & libc::RENAME_NOREPLACE != 0 => return Err(libc::EEXIST),
+            // two links to the same file, POSIX says do nothing
+            Some(target) if target == source => return Ok(()),
+            Some(target) if self.is_immutable(target) => return Err(libc::EPERM),
+            Some(target) => {
+                let source_is_dir = self.files[&source].attrs.kind == FileType::Directory;
+                let target_dir = self.files.get(&target).filter(|t| t.attrs.kind == FileType::Directory);
+                match target_dir {
+                    Some(_) if !source_is_dir => return Err(libc::EISDIR),
+                    Some(dir) if !dir.children.is_empty() => return Err(libc::ENOTEMPTY),
+                    Some(_) => self.remove_subtree(target),
+                    None if source_is_dir => return Err(libc::ENOTDIR),
+                    None => self.unlink_entry(newparent, new_name, target),
+                }
+            }
+            None if parent != newparent && self.dir_is_full(newparent) => return Err(libc::ENOSPC),
+            None => {}
+        }
+
+        self.rename(parent, old_name.to_string(), newparent, new_name.to_string())
+    }
+
+    pub fn rename(&mut self, old_parent_inode: u64, file_old_name: String, new_parent_inode: u64, file_new_name: String,) -> Result<(), i32> {
+
+        let child_inode = self.find_child(old_parent_inode, &file_old_name).ok_or(ENOENT)?;
+
+        let mut moved_directory = false;
+        if let Some(child) = self.files.get_mut(&child_inode) {
+            child.move_link(old_parent_inode, &file_old_name, new_parent_inode, &file_new_name);
+            moved_directory = child.attrs.kind == FileType::Directory && old_parent_inode != new_parent_inode;
+        }
+        if moved_directory {
+            self.adjust_nlink(old_parent_inode, -1);
+            self.adjust_nlink(new_parent_inode, 1);
+        }
+        if !self.inode_block_table.contains_key(&child_inode) {
+            println!("inode {} has no block mapping, rename kept in memory only", child_inode);
+        } else if let Err(e) = self.write_entry(child_inode) {
+            if let Some(child) = self.files.get_mut(&child_inode) {
+                child.move_link(new_parent_inode, &file_new_name, old_parent_inode, &file_old_name);
+            }
+            if moved_directory {
+                self.adjust_nlink(old_parent_inode, 1);
+                self.adjust_nlink(new_parent_inode, -1);
+            }
+            return Err(io_errno(&e));
+        }
+
+        if let Some(parent_file) = self.files.get_mut(&old_parent_inode) {
+            parent_file.remove_child(&file_old_name, child_inode);
+        }
+
+        if let Some(new_parent) = self.files.get_mut(&new_parent_inode) {
+            new_parent.add_child(&file_new_name, child_inode);
+        }
+        Ok(())
+    }
+
+}
+
+
+impl Drop for QRFileSystem {
+    fn drop(&mut self) {
+        if self.flushed {
+            return;
+        }
+        let _ = self.flush();
+    }
+}
+
+
+impl Filesystem for QRFileSystem {
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let _ = self.write_dirty_if_due();
+        match self.files.get(&ino) {
+            Some(file) => {
+                let attr = reported_attrs(&file.attrs);
+                let ttl = Duration::from_secs(1);
+                reply.attr(&ttl, &attr);
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+
+    fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        let old_name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        let new_name = match newname.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.rename_entry(parent, old_name, newparent, new_name, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        match self.write_handle(ino, fh, offset as usize, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn copy_file_range(&mut self, _req: &Request, ino_in: u64, _fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, _flags: u32, reply: ReplyWrite) {
+        match self.copy_range(ino_in, offset_in as usize, ino_out, fh_out, offset_out as usize, len) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn fallocate(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        match self.allocate_range(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn lseek(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        match self.seek(ino, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+
+    fn access(&mut self, _req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+    
+        println!("Calling to access...");
+
+        match self.check_access(ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        let file_name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let attr = match self.create_file(parent, file_name, mode, umask, req.uid(), req.gid()) {
+            Ok(a) => a,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let ttl = Duration::from_secs(1);
+
+        let fh = self.open_handle(attr.ino, flags);
+
+        // the last argument is FOPEN_* reply flags, not the open flags
+        reply.created(&ttl, &reported_attrs(&attr), self.generation(attr.ino), fh, 0);
+    }
+
+
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.open_file(ino, flags) {
+            Ok(fh) => {
+                println!("open called for ino={}", ino);
+                reply.opened(fh, 0);
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        match self.release_handle(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let changes = SetAttrs { mode, uid, gid, size, atime, mtime, ctime, flags };
+        match self.set_attributes(ino, changes) {
+            Ok(attrs) => reply.attr(&Duration::new(1, 0), &reported_attrs(&attrs)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) { 
+        let file_name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.make_dir(parent, file_name, mode, umask, req.uid(), req.gid()) {
+            Ok(attrs) => reply.entry(&Duration::new(1, 0), &reported_attrs(&attrs), self.generation(attrs.ino)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.remove_dir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn symlink(&mut self, req: &Request, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        let file_name = match link_name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.make_symlink(parent, file_name, target.as_os_str().as_bytes(), req.uid(), req.gid()) {
+            Ok(attrs) => reply.entry(&Duration::from_secs(1), &reported_attrs(&attrs), self.generation(attrs.ino)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn mknod(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        let file_name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        match self.make_node(parent, file_name, mode & !umask, rdev, req.uid(), req.gid()) {
+            Ok(attrs) => reply.entry(&Duration::from_secs(1), &reported_attrs(&attrs), self.generation(attrs.ino)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.read_link(ino) {
+            Ok(target) => reply.data(target),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn setxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        match self.set_xattr(ino, name, value, flags, position) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let value = match self.get_xattr(ino, name) {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        if size == 0 {
+            reply.size(value.len() as u32);
+        } else if (size as usize) < value.len() {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(value);
+        }
+    }
+
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let names = match self.list_xattrs(ino) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if (size as usize) < names.len() {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_xattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn link(&mut self, _req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let new_name = match newname.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.link_entry(ino, newparent, new_name) {
+            Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation(ino)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        match self.unlink_name(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        let _ = self.write_dirty_if_due();
+        match self.read_data(ino, offset as usize, size as usize) {
+            Ok(bytes) => reply.data(&bytes),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _ = self.write_dirty_if_due();
+
+        match self.lookup_name(parent, name) {
+            Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, self.generation(attr.ino)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.open_dir(ino) {
+            Some(fh) => reply.opened(fh, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+
+    fn readdir(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) { 
+        let _ = self.write_dirty_if_due();
+        match self.read_dir(ino, fh, offset, |inode, next_offset, kind, name| reply.add(inode, next_offset, kind, name)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.open_dirs.remove(&fh);
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let _ = self.write_dirty_if_due();
+        let sb = self.superblock;
+        let free_blocks = self.free_blocks();
+        let entries = self.files.len() as u64;
+        reply.statfs(
+            sb.total_blocks,
+            free_blocks,
+            free_blocks,
+            entries + free_blocks,
+            free_blocks,
+            sb.block_size.try_into().unwrap(),
+            MAX_NAME_SIZE.try_into().unwrap(),
+            sb.block_size.try_into().unwrap(),
+        );
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        if self.read_only {
+            reply.ok();
+            return;
+        }
+        match self.flush_entry(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(io_errno(&e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        if self.read_only {
+            reply.ok();
+            return;
+        }
+        match self.flush_entry(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(io_errno(&e)),
+        }
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        if self.read_only {
+            reply.ok();
+            return;
+        }
+        match self.flush_entry(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(io_errno(&e)),
+        }
+    }
+
+    fn ioctl(&mut self, _req: &Request, ino: u64, _fh: u64, _flags: u32, cmd: u32, in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        let file = match self.files.get_mut(&ino) {
+            Some(f) => f,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if cmd == libc::FS_IOC_GETFLAGS as u32 {
+            reply.ioctl(0, &file.attrs.flags.to_le_bytes());
+            return;
+        }
+        // the kernel reads these before it passes on a FS_IOC_SETFLAGS
+        if cmd == FS_IOC_FSGETXATTR {
+            let mut fsxattr = [0u8; 28];
+            if file.attrs.flags & FS_IMMUTABLE_FL != 0 {
+                fsxattr[..4].copy_from_slice(&FS_XFLAG_IMMUTABLE.to_le_bytes());
+            }
+            reply.ioctl(0, &fsxattr);
+            return;
+        }
+        if cmd != libc::FS_IOC_SETFLAGS as u32 {
+            reply.error(libc::ENOTTY);
+            return;
+        }
+        if self.read_only {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let new_flags = match in_data.get(..4) {
+            Some(b) => u32::from_le_bytes(b.try_into().unwrap()),
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let previous = file.attrs;
+        file.attrs.flags = new_flags;
+        file.attrs.ctime = SystemTime::now();
+        if let Err(e) = self.write_entry(ino) {
+            if let Some(file) = self.files.get_mut(&ino) {
+                file.attrs = previous;
+            }
+            reply.error(io_errno(&e));
+            return;
+        }
+        reply.ioctl(0, &[]);
+    }
+
+
+    fn destroy(&mut self) {
+        let _ = self.flush();
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the pre-chain layout, header then data, nothing after it
+    fn baseline_entry(inode: u64, parent: u64, name: &str, is_dir: bool, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&inode.to_le_bytes());
+        buf.extend_from_slice(&parent.to_le_bytes());
+        buf.extend_from_slice(&fixed_name(name));
+        buf.push(is_dir as u8);
+        buf.extend_from_slice(&0o755u16.to_le_bytes());
+        for _ in 0..4 {
+            buf.extend_from_slice(&1_700_000_000u64.to_le_bytes());
+        }
+        buf.extend_from_slice(&4096u32.to_le_bytes());
+        buf.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn load_image(image: Vec<u8>) -> QRFileSystem {
+        let mut fs = QRFileSystem::from_disk(Disk::Memory(Cursor::new(image)), false).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        fs
+    }
+
+    fn with_geometry(block_count: u64, block_size: u64) -> QRFileSystem {
+        let mut disk = Disk::Memory(Cursor::new(Vec::new()));
+        format_disk(&mut disk, &SuperBlock::new(block_count, block_size)).unwrap();
+        let mut fs = QRFileSystem::from_disk(disk, false).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        fs
+    }
+
+    fn disk_bytes(fs: &mut QRFileSystem) -> Vec<u8> {
+        fs.flush().unwrap();
+        disk_bytes_unflushed(fs)
+    }
+
+    fn disk_bytes_unflushed(fs: &QRFileSystem) -> Vec<u8> {
+        match &fs.disk {
+            Disk::Memory(c) => c.get_ref().clone(),
+            Disk::File(_) => unreachable!(),
+        }
+    }
+
+    fn add_file(fs: &mut QRFileSystem, parent: u64, name: &str, data: Option<Vec<u8>>) -> u64 {
+        let inode = fs.next_inode();
+        let size = data.as_ref().map_or(0, |d| d.len() as u64);
+        fs.push(inode, name.to_string(), data, parent, &get_default_attrs(inode, size, false)).unwrap();
+        inode
+    }
+
+    fn add_dir(fs: &mut QRFileSystem, parent: u64, name: &str) -> u64 {
+        let inode = fs.next_inode();
+        fs.push(inode, name.to_string(), None, parent, &get_default_attrs(inode, 0, true)).unwrap();
+        inode
+    }
+
+    fn add_symlink(fs: &mut QRFileSystem, parent: u64, name: &str, target: &str) -> u64 {
+        let mut attrs = get_default_attrs(0, target.len() as u64, false);
+        attrs.kind = FileType::Symlink;
+        fs.make_entry(parent, name, Some(target.as_bytes().to_vec()), &attrs).unwrap().ino
+    }
+
+    fn snapshot_names(fs: &QRFileSystem, fh: u64) -> Vec<String> {
+        fs.open_dirs[&fh].iter().skip(2).map(|(_, _, name)| name.clone()).collect()
+    }
+
+    #[test]
+    fn resolve_path_follows_relative_symlink_chains() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let a = add_dir(&mut fs, 1, "a");
+        let b = add_dir(&mut fs, a, "b");
+        let real = add_file(&mut fs, b, "real", None);
+        add_symlink(&mut fs, a, "l1", "b/real");
+        add_symlink(&mut fs, 1, "l2", "a/l1");
+        let c = add_dir(&mut fs, 1, "c");
+        let l3 = add_symlink(&mut fs, c, "l3", "../l2");
+        add_symlink(&mut fs, a, "to_b", "./b/");
+        add_symlink(&mut fs, c, "abs", "/a/b");
+
+        assert_eq!(fs.resolve_path("/c/l3", true), Ok(real));
+        assert_eq!(fs.resolve_path("/c/l3", false), Ok(l3));
+        assert_eq!(fs.resolve_path("a/to_b/real", true), Ok(real));
+        assert_eq!(fs.resolve_path("c/abs/../b/real", true), Ok(real));
+        assert_eq!(fs.resolve_path("a/to_b/real", false), Err(libc::ENOTDIR));
+        assert_eq!(fs.resolve_path("/c/l3/", true), Err(libc::ENOTDIR));
+
+        add_symlink(&mut fs, 1, "dangling", "a/missing");
+        assert_eq!(fs.resolve_path("dangling", true), Err(ENOENT));
+    }
+
+    #[test]
+    fn resolve_path_stops_symlink_loops() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let me = add_symlink(&mut fs, 1, "me", "me");
+        add_symlink(&mut fs, 1, "x", "y");
+        add_symlink(&mut fs, 1, "y", "./x");
+        let d = add_dir(&mut fs, 1, "d");
+        add_symlink(&mut fs, d, "up", "../d/up");
+
+        assert_eq!(fs.resolve_path("me", true), Err(libc::ELOOP));
+        assert_eq!(fs.resolve_path("me", false), Ok(me));
+        assert_eq!(fs.resolve_path("/x", true), Err(libc::ELOOP));
+        assert_eq!(fs.resolve_path("d/up/anything", true), Err(libc::ELOOP));
+
+        let mut target = "end".to_string();
+        let end = add_file(&mut fs, 1, "end", None);
+        for i in 0..MAX_SYMLINK_HOPS {
+            let name = format!("hop{}", i);
+            add_symlink(&mut fs, 1, &name, &target);
+            target = name;
+        }
+        assert_eq!(fs.resolve_path(&target, true), Ok(end));
+        add_symlink(&mut fs, 1, "hop40", &target);
+        assert_eq!(fs.resolve_path("hop40", true), Err(libc::ELOOP));
+    }
+
+    #[test]
+    fn defragment_keeps_siblings_contiguous_and_data_intact() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let dirs: Vec<u64> = (0..4).map(|d| add_dir(&mut fs, 1, &format!("d{}", d))).collect();
+        let mut files = Vec::new();
+        for i in 0..40 {
+            files.push(add_file(&mut fs, dirs[i % 4], &format!("f{}", i), Some(vec![i as u8; 10])));
+        }
+        for (i, &inode) in files.iter().enumerate() {
+            if i % 3 == 0 {
+                fs.write_data(inode, 0, &vec![i as u8; 1500]).unwrap();
+            }
+        }
+        for &inode in files.iter().step_by(5) {
+            let (parent, name) = (fs.files[&inode].parent, fixed_name_to_str(&fs.files[&inode].name).to_string());
+            fs.unlink_entry(parent, &name, inode);
+        }
+        fs.flush().unwrap();
+        let before: HashMap<u64, Vec<u8>> = fs.files.iter().map(|(&i, f)| (i, serialize_fs_entry_to_disk(f))).collect();
+
+        fs.defragment_inode_blocks().unwrap();
+
+        for &dir in &dirs {
+            let blocks: Vec<u64> = fs.files[&dir].children.iter().flat_map(|(_, i)| fs.inode_block_table[i].clone()).collect();
+            assert!(blocks.windows(2).all(|w| w[1] == w[0] + 1), "children of {} are not contiguous: {:?}", dir, blocks);
+        }
+        let used: HashSet<u64> = fs.inode_block_table.values().flatten().copied().collect();
+        for block in fs.superblock.data_start..fs.superblock.total_blocks {
+            assert_eq!(bitmap_get(&fs.bitmap, block), used.contains(&block), "bitmap disagrees about block {}", block);
+        }
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files.len(), before.len());
+        for (inode, serialized) in &before {
+            assert_eq!(&serialize_fs_entry_to_disk(&fs.files[inode]), serialized);
+        }
+    }
+
+    #[test]
+    fn fsck_repairs_desynced_table_and_bitmap() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let dir = add_dir(&mut fs, 1, "dir");
+        let unmapped = add_file(&mut fs, dir, "unmapped", Some(b"still here".to_vec()));
+        let lost = add_file(&mut fs, dir, "lost", Some(b"lost data".to_vec()));
+        add_file(&mut fs, 1, "lost", None);
+        let linked = add_file(&mut fs, 1, "linked", None);
+        fs.files.get_mut(&linked).unwrap().links.push((4242, fixed_name("gone")));
+
+        let unmapped_blocks = fs.inode_block_table.remove(&unmapped).unwrap();
+        assert_eq!(fs.set_attributes(unmapped, SetAttrs { mode: Some(0o600), ..Default::default() }), Err(libc::EIO));
+        fs.inode_block_table.insert(9999, vec![1500]);
+        bitmap_set_bit(&mut fs.bitmap, 1500);
+        bitmap_set_bit(&mut fs.bitmap, 1800);
+        let dir_block = fs.inode_block_table[&dir][0];
+        bitmap_clear_bit(&mut fs.bitmap, dir_block);
+        fs.files.get_mut(&lost).unwrap().parent = 777;
+        fs.files.get_mut(&dir).unwrap().remove_child("lost", lost);
+
+        let report = fs.fsck().unwrap();
+        assert_eq!(report, FsckReport { stale_mappings: 1, orphaned_blocks: unmapped_blocks.len() + 2, unmarked_blocks: 1, reattached: 1, dropped_links: 1, remapped: 1 });
+        assert_eq!(fs.fsck().unwrap(), FsckReport::default());
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&unmapped].data.as_deref(), Some(&b"still here"[..]));
+        assert_eq!(fs.resolve_path(&format!("lost.dup.{}", lost), false), Ok(lost));
+        assert_eq!(fs.files[&lost].data.as_deref(), Some(&b"lost data"[..]));
+        assert!(fs.files[&linked].links.is_empty());
+        assert_eq!(fs.files[&linked].attrs.nlink, 1);
+        let used: HashSet<u64> = fs.inode_block_table.values().flatten().copied().collect();
+        for block in fs.superblock.data_start..fs.superblock.total_blocks {
+            assert_eq!(bitmap_get(&fs.bitmap, block), used.contains(&block), "bitmap disagrees about block {}", block);
+        }
+    }
+
+    #[test]
+    fn reads_follow_relatime_and_defer_the_atime() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"abc".to_vec()));
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        let attrs = &mut fs.files.get_mut(&inode).unwrap().attrs;
+        (attrs.atime, attrs.mtime, attrs.ctime) = (past, past, past);
+        fs.write_entry(inode).unwrap();
+
+        assert_eq!(fs.read_data(inode, 1, 10).unwrap(), b"bc");
+        let first = fs.files[&inode].attrs.atime;
+        assert!(first > past);
+        assert!(fs.dirty.contains(&inode));
+        assert_eq!(to_seconds(load_image(disk_bytes_unflushed(&fs)).files[&inode].attrs.atime), to_seconds(past));
+
+        fs.flush().unwrap();
+        fs.read_data(inode, 0, 3).unwrap();
+        assert_eq!(fs.files[&inode].attrs.atime, first);
+        assert!(fs.dirty.is_empty());
+
+        fs.files.get_mut(&inode).unwrap().attrs.atime = first - RELATIME_INTERVAL - Duration::from_secs(1);
+        fs.files.get_mut(&inode).unwrap().attrs.mtime = first - RELATIME_INTERVAL - Duration::from_secs(2);
+        fs.read_data(inode, 0, 3).unwrap();
+        assert!(fs.files[&inode].attrs.atime >= first);
+    }
+
+    #[test]
+    fn deferred_writes_are_flushed_once_the_oldest_is_due() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let a = add_file(&mut fs, 1, "a", Some(vec![0; 10]));
+        let b = add_file(&mut fs, 1, "b", Some(vec![0; 10]));
+
+        fs.write_data(a, 0, b"x").unwrap();
+        let since = fs.dirty_since.unwrap();
+        fs.write_data(b, 0, b"y").unwrap();
+        assert_eq!(fs.dirty_since, Some(since));
+        assert_eq!(fs.dirty.len(), 2);
+
+        fs.write_dirty_if_due().unwrap();
+        assert_eq!(fs.dirty.len(), 2);
+
+        fs.dirty_since = Some(Instant::now() - DIRTY_INTERVAL);
+        fs.write_dirty_if_due().unwrap();
+        assert!(fs.dirty.is_empty());
+        assert_eq!(fs.dirty_since, None);
+        let on_disk = load_image(disk_bytes_unflushed(&fs));
+        assert_eq!(on_disk.files[&a].data.as_ref().unwrap()[0], b'x');
+        assert_eq!(on_disk.files[&b].data.as_ref().unwrap()[0], b'y');
+
+        fs.write_data(a, 1, b"x").unwrap();
+        fs.dirty_since = Some(Instant::now() - DIRTY_INTERVAL);
+        fs.write_data(b, 1, b"y").unwrap();
+        assert!(fs.dirty.is_empty());
+    }
+
+    #[test]
+    fn allocation_writes_back_only_its_bitmap_block() {
+        let mut fs = with_geometry(10_000, 512);
+        let sb = fs.superblock;
+        assert_eq!(sb.bitmap_blocks, 3);
+        let first_bitmap_byte = |fs: &QRFileSystem, bitmap_block: u64| disk_bytes_unflushed(fs)[((sb.bitmap_start + bitmap_block) * sb.block_size) as usize];
+
+        write_u64(&mut fs.disk, (sb.bitmap_start + 1) * sb.block_size, u64::MAX).unwrap();
+        write_u64(&mut fs.disk, sb.bitmap_start * sb.block_size + 9000 / 8, u64::MAX).unwrap();
+        fs.next_free_hint = 9000;
+        let inode = add_file(&mut fs, 1, "far", None);
+        let block = fs.inode_block_table[&inode][0];
+        assert_eq!(block, 9000);
+        assert!(bitmap_get(&fs.bitmap, block));
+
+        let on_disk = disk_bytes_unflushed(&fs);
+        let bitmap_on_disk = &on_disk[(sb.bitmap_start * sb.block_size) as usize..((sb.bitmap_start + sb.bitmap_blocks) * sb.block_size) as usize];
+        assert!(bitmap_get(bitmap_on_disk, block));
+        assert_eq!(first_bitmap_byte(&fs, 1), 0xff);
+
+        fs.unlink_entry(1, "far", inode);
+        assert!(!bitmap_get(&fs.bitmap, block));
+        assert_eq!(first_bitmap_byte(&fs, 1), 0xff);
+        assert_eq!(fs.next_free_hint, block);
+    }
+
+    #[test]
+    fn sticky_directory_mode_survives_remount() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let tmp = fs.make_dir(1, "tmp", 0o1777, 0o022, 0, 0).unwrap().ino;
+        assert_eq!(fs.files[&tmp].attrs.perm, 0o1755);
+        let attrs = fs.set_attributes(tmp, SetAttrs { mode: Some(libc::S_IFDIR | 0o1777), ..Default::default() }).unwrap();
+        assert_eq!(attrs.perm, 0o1777);
+
+        let setgid = fs.make_dir(1, "shared", 0o2775, 0, 0, 0).unwrap().ino;
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&tmp].attrs.perm, 0o1777);
+        assert_eq!(fs.files[&setgid].attrs.perm, 0o2775);
+    }
+
+    #[test]
+    fn rewinddir_sees_entries_changed_during_listing() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let dir = add_dir(&mut fs, 1, "d");
+        let gone = add_file(&mut fs, dir, "gone", None);
+        add_file(&mut fs, dir, "kept", None);
+
+        let fh = fs.open_dir(dir).unwrap();
+        assert_eq!(snapshot_names(&fs, fh), ["gone", "kept"]);
+
+        fs.unlink_entry(dir, "gone", gone);
+        add_file(&mut fs, dir, "new", None);
+        assert_eq!(snapshot_names(&fs, fh), ["gone", "kept"]);
+
+        fs.rewind_dir(dir, fh);
+        assert_eq!(snapshot_names(&fs, fh), ["kept", "new"]);
+
+        fs.rewind_dir(dir, fh + 1);
+        assert!(!fs.open_dirs.contains_key(&(fh + 1)));
+    }
+
+    #[test]
+    fn baseline_image_survives_migration() {
+        let bs = BLOCK_SIZE as usize;
+        // 88 header bytes plus this fill the block exactly
+        let data: Vec<u8> = (0..424).map(|i| (i % 251) as u8 + 1).collect();
+        let mut image = vec![0u8; (BLOCK_COUNT * BLOCK_SIZE) as usize];
+        // legacy bitmap: blocks 0 and 1 reserved, root in 2, the file in 3
+        image[0] = 0b1111;
+        image[bs..bs + 8].copy_from_slice(&2u64.to_le_bytes());
+        let root = baseline_entry(1, 0, "/", true, &[]);
+        let file = baseline_entry(2, 1, "full.bin", false, &data);
+        assert_eq!(file.len(), bs);
+        image[2 * bs..2 * bs + root.len()].copy_from_slice(&root);
+        image[3 * bs..4 * bs].copy_from_slice(&file);
+
+        let mut fs = load_image(image);
+        assert_eq!(fs.format_version, FORMAT_VERSION);
+        assert_eq!(fs.files.len(), 2);
+        assert_eq!(fs.files[&2].data.as_deref(), Some(&data[..]));
+        assert_eq!(fs.find_child(1, "full.bin"), Some(2));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files.len(), 2);
+        assert_eq!(fs.files[&2].attrs.size, 424);
+        assert_eq!(fs.files[&2].data.as_deref(), Some(&data[..]));
+    }
+
+    #[test]
+    fn in_memory_gives_a_loaded_file_system_without_a_file() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        assert_eq!(disk_bytes_unflushed(&fs).len() as u64, BLOCK_COUNT * BLOCK_SIZE);
+        assert_eq!(fs.format_version, FORMAT_VERSION);
+        assert_eq!(fs.files[&1].attrs.kind, FileType::Directory);
+
+        let dir = fs.make_dir(1, "docs", 0o755, 0, 0, 0).unwrap().ino;
+        let file = fs.make_entry(dir, "a.txt", None, &get_default_attrs(0, 0, false)).unwrap().ino;
+        fs.write_data(file, 0, b"kept in a Vec").unwrap();
+        assert_eq!(fs.resolve_path("/docs/a.txt", false), Ok(file));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.resolve_path("/docs/a.txt", false), Ok(file));
+        assert_eq!(fs.files[&file].data.as_deref(), Some(&b"kept in a Vec"[..]));
+    }
+
+    #[test]
+    fn duplicate_siblings_are_both_reachable_after_load() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let first = add_file(&mut fs, 1, "dup", Some(b"first".to_vec()));
+        let second = add_file(&mut fs, 1, "dup", Some(b"second".to_vec()));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.find_child(1, "dup"), Some(first));
+        assert_eq!(fs.find_child(1, &format!("dup.dup.{}", second)), Some(second));
+        assert_eq!(fs.files[&first].data.as_deref(), Some(&b"first"[..]));
+        assert_eq!(fs.files[&second].data.as_deref(), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn writes_past_the_file_quota_get_efbig_while_space_remains() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        fs.max_file_blocks = Some(2);
+        let inode = add_file(&mut fs, 1, "capped", Some(b"small".to_vec()));
+        let fh = fs.open_handle(inode, libc::O_WRONLY);
+
+        assert_eq!(fs.write_handle(inode, fh, 0, &[7; 800]), Ok(800));
+        assert_eq!(fs.write_handle(inode, fh, 800, &[7; 300]), Err(libc::EFBIG));
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(5000), ..Default::default() }), Err(libc::EFBIG));
+        assert!(fs.free_blocks() > 1000);
+        assert_eq!(fs.files[&inode].attrs.size, 800);
+        assert_eq!(fs.inode_block_table[&inode].len(), 2);
+    }
+
+    #[test]
+    fn names_that_would_be_cut_mid_character_are_rejected() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let attrs = get_default_attrs(0, 0, false);
+        let too_long = format!("{}é", "a".repeat(24));
+        assert_eq!(fs.make_entry(1, &too_long, None, &attrs), Err(libc::ENAMETOOLONG));
+        assert_eq!(fs.files.len(), 1);
+        assert_eq!(fixed_name_to_str(&fixed_name(&too_long)), "a".repeat(24));
+
+        let fits = format!("{}é", "a".repeat(23));
+        let inode = fs.make_entry(1, &fits, None, &attrs).unwrap().ino;
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.find_child(1, &fits), Some(inode));
+    }
+
+    #[test]
+    fn create_applies_mode_and_umask() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let attr = fs.create_file(1, "script", 0o4775, 0o022, 1000, 100).unwrap();
+        assert_eq!(attr.perm, 0o4755);
+        assert_eq!((attr.uid, attr.gid), (1000, 100));
+
+        let private = fs.create_file(1, "key", 0o600, 0o022, 0, 0).unwrap().ino;
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&attr.ino].attrs.perm, 0o4755);
+        assert_eq!(fs.files[&private].attrs.perm, 0o600);
+    }
+
+    #[test]
+    fn dir_usage_map_sums_each_top_directory() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let photos = add_dir(&mut fs, 1, "photos");
+        let trip = add_dir(&mut fs, photos, "trip");
+        let big = add_file(&mut fs, trip, "big", Some(vec![1; 2000]));
+        add_file(&mut fs, photos, "small", Some(b"x".to_vec()));
+        let empty = add_dir(&mut fs, 1, "empty");
+        add_file(&mut fs, 1, "loose", Some(vec![2; 2000]));
+
+        let usage = fs.dir_usage_map();
+        let blocks = |inodes: &[u64]| inodes.iter().map(|i| fs.inode_block_table[i].len() as u64).sum::<u64>();
+        let small = fs.find_child(photos, "small").unwrap();
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[&photos], blocks(&[photos, trip, big, small]));
+        assert_eq!(usage[&empty], blocks(&[empty]));
+
+        fs.files.get_mut(&trip).unwrap().add_child("loop", photos);
+        assert_eq!(fs.dir_usage_map()[&photos], blocks(&[photos, trip, big, small]));
+    }
+
+    #[test]
+    fn u64_fields_outside_the_disk_are_rejected() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let len = BLOCK_COUNT * BLOCK_SIZE;
+        assert!(write_u64(&mut fs.disk, len - 8, 42).is_ok());
+        assert_eq!(read_u64(&mut fs.disk, len - 8).unwrap(), 42);
+        for offset in [len - 7, len, u64::MAX - 3] {
+            assert_eq!(read_u64(&mut fs.disk, offset).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(write_u64(&mut fs.disk, offset, 1).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(disk_bytes(&mut fs).len() as u64, len);
+    }
+
+    #[test]
+    fn drop_persists_unflushed_data_and_skips_a_second_flush() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(vec![0; 10]));
+        fs.flush().unwrap();
+        assert!(fs.flushed);
+        fs.write_data(inode, 0, b"late").unwrap();
+        assert!(fs.dirty.contains(&inode));
+        assert!(!fs.flushed);
+        fs.flush().unwrap();
+        assert!(fs.flushed);
+
+        let path = std::env::temp_dir().join(format!("qrfs_drop_test_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        initialize_new_disk(path).unwrap();
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(vec![0; 10]));
+        fs.write_data(inode, 2, b"dropped").unwrap();
+        assert!(fs.dirty.contains(&inode));
+        drop(fs);
+
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        assert_eq!(&fs.files[&inode].data.as_ref().unwrap()[2..9], b"dropped");
+        drop(fs);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn access_for_writing_on_a_read_only_mount_gets_erofs() {
+        let path = std::env::temp_dir().join(format!("qrfs_access_test_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        initialize_new_disk(path).unwrap();
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"data".to_vec()));
+        drop(fs);
+
+        let mut fs = QRFileSystem::new_read_only(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        assert_eq!(fs.check_access(inode, libc::W_OK), Err(libc::EROFS));
+        assert_eq!(fs.check_access(1, libc::R_OK | libc::W_OK), Err(libc::EROFS));
+        assert_eq!(fs.check_access(inode, libc::R_OK), Ok(()));
+        assert_eq!(fs.check_access(99, libc::F_OK), Err(ENOENT));
+        drop(fs);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn created_entries_are_found_right_away() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        for n in 0..50 {
+            let name = format!("new{}", n);
+            let attrs = fs.make_entry(1, &name, None, &get_default_attrs(0, 0, false)).unwrap();
+            assert_eq!(fs.find_child(1, &name), Some(attrs.ino));
+            assert_eq!(fs.files[&attrs.ino].attrs.ino, attrs.ino);
+            assert!(fs.inode_block_table.contains_key(&attrs.ino));
+        }
+    }
+
+    #[test]
+    fn unlinked_open_file_keeps_its_blocks_until_the_last_release() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "tmp", Some(vec![5; 1500]));
+        let free_before = fs.free_blocks();
+        let first = fs.open_handle(inode, libc::O_RDWR);
+        let second = fs.open_handle(inode, libc::O_RDONLY);
+
+        fs.unlink_entry(1, "tmp", inode);
+        assert_eq!(fs.find_child(1, "tmp"), None);
+        assert_eq!(fs.files[&inode].attrs.nlink, 0);
+        assert_eq!(fs.free_blocks(), free_before);
+        assert_eq!(fs.write_handle(inode, first, 0, b"still here"), Ok(10));
+        assert_eq!(fs.read_data(inode, 0, 10).unwrap(), b"still here");
+
+        let reloaded = load_image(disk_bytes(&mut fs));
+        assert!(!reloaded.files.contains_key(&inode));
+        assert!(!reloaded.inode_block_table.contains_key(&inode));
+
+        fs.release_handle(inode, first).unwrap();
+        assert!(fs.files.contains_key(&inode));
+        fs.release_handle(inode, second).unwrap();
+        assert!(!fs.files.contains_key(&inode));
+        assert!(fs.free_blocks() > free_before);
+    }
+
+    #[test]
+    fn hard_links_share_the_inode_until_the_last_unlink() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let dir = add_dir(&mut fs, 1, "other");
+        let inode = add_file(&mut fs, 1, "orig", Some(b"shared".to_vec()));
+
+        let attr = fs.link_entry(inode, dir, "alias").unwrap();
+        assert_eq!((attr.ino, attr.nlink), (inode, 2));
+        assert_eq!(fs.link_entry(inode, dir, "alias"), Err(libc::EEXIST));
+        assert_eq!(fs.link_entry(dir, 1, "dirlink"), Err(libc::EPERM));
+
+        let mut fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.find_child(1, "orig"), Some(inode));
+        assert_eq!(fs.find_child(dir, "alias"), Some(inode));
+        assert_eq!(fs.files[&inode].attrs.nlink, 2);
+
+        fs.unlink_name(1, "orig").unwrap();
+        assert_eq!(fs.find_child(1, "orig"), None);
+        assert_eq!(fs.files[&inode].attrs.nlink, 1);
+        assert_eq!(fs.read_data(inode, 0, 6).unwrap(), b"shared");
+
+        fs.unlink_name(dir, "alias").unwrap();
+        assert!(!fs.files.contains_key(&inode));
+        assert_eq!(fs.unlink_name(dir, "alias"), Err(ENOENT));
+    }
+
+    #[test]
+    fn nlink_counts_subdirectories_and_names() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let top = add_dir(&mut fs, 1, "top");
+        let a = add_dir(&mut fs, top, "a");
+        add_dir(&mut fs, top, "b");
+        let file = add_file(&mut fs, top, "f", None);
+        assert_eq!(fs.files[&1].attrs.nlink, 3);
+        assert_eq!(fs.files[&top].attrs.nlink, 4);
+        assert_eq!(fs.files[&a].attrs.nlink, 2);
+        assert_eq!(fs.files[&file].attrs.nlink, 1);
+
+        assert_eq!(fs.remove_dir(top, "f"), Err(libc::ENOTDIR));
+        assert_eq!(fs.remove_dir(1, "top"), Err(libc::ENOTEMPTY));
+        fs.remove_dir(top, "a").unwrap();
+        assert_eq!(fs.files[&top].attrs.nlink, 3);
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&1].attrs.nlink, 3);
+        assert_eq!(fs.files[&top].attrs.nlink, 3);
+        assert_eq!(fs.files[&file].attrs.nlink, 1);
+        assert!(!fs.files.contains_key(&a));
+    }
+
+    #[test]
+    fn owners_survive_a_reload_and_chown() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let file = fs.create_file(1, "mine", 0o644, 0, 1000, 1001).unwrap().ino;
+        let dir = fs.make_dir(1, "ours", 0o755, 0, 1000, 50).unwrap().ino;
+
+        let mut fs = load_image(disk_bytes(&mut fs));
+        assert_eq!((fs.files[&file].attrs.uid, fs.files[&file].attrs.gid), (1000, 1001));
+        assert_eq!((fs.files[&dir].attrs.uid, fs.files[&dir].attrs.gid), (1000, 50));
+
+        let attr = fs.set_attributes(file, SetAttrs { uid: Some(0), gid: Some(7), ..Default::default() }).unwrap();
+        assert_eq!((attr.uid, attr.gid), (0, 7));
+        fs.set_attributes(dir, SetAttrs { gid: Some(8), ..Default::default() }).unwrap();
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!((fs.files[&file].attrs.uid, fs.files[&file].attrs.gid), (0, 7));
+        assert_eq!((fs.files[&dir].attrs.uid, fs.files[&dir].attrs.gid), (1000, 8));
+    }
+
+    #[test]
+    fn symlink_targets_read_back_unchanged() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let target = b"../some where/\xff\xfe";
+        let attr = fs.make_symlink(1, "link", target, 1000, 1000).unwrap();
+        assert_eq!((attr.kind, attr.size, attr.perm), (FileType::Symlink, target.len() as u64, 0o777));
+        let file = add_file(&mut fs, 1, "plain", None);
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&attr.ino].attrs.kind, FileType::Symlink);
+        assert_eq!(fs.read_link(attr.ino), Ok(&target[..]));
+        assert_eq!(fs.read_link(file), Err(libc::EINVAL));
+        assert_eq!(fs.read_link(99), Err(ENOENT));
+    }
+
+    #[test]
+    fn timestamps_keep_their_nanoseconds() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", None);
+        let atime = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_001, 1);
+        let ctime = UNIX_EPOCH + Duration::new(1_700_000_002, 999_999_999);
+        fs.set_attributes(inode, SetAttrs {
+            atime: Some(fuser::TimeOrNow::SpecificTime(atime)),
+            mtime: Some(fuser::TimeOrNow::SpecificTime(mtime)),
+            ctime: Some(ctime),
+            ..Default::default()
+        }).unwrap();
+
+        let fs = load_image(disk_bytes(&mut fs));
+        let attrs = fs.files[&inode].attrs;
+        assert_eq!((attrs.atime, attrs.mtime, attrs.ctime), (atime, mtime, ctime));
+    }
+
+    #[test]
+    fn truncating_to_a_size_the_disk_cannot_hold_fails_before_allocating() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"keep".to_vec()));
+        let free = fs.free_blocks();
+
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(i64::MAX as u64), ..Default::default() }), Err(libc::ENOSPC));
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(u64::MAX), ..Default::default() }), Err(libc::ENOSPC));
+        fs.max_file_blocks = Some(4);
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(i64::MAX as u64), ..Default::default() }), Err(libc::EFBIG));
+        assert_eq!(fs.files[&inode].data.as_deref(), Some(&b"keep"[..]));
+        assert_eq!(fs.free_blocks(), free);
+
+        fs.max_file_blocks = None;
+        let fits = free as usize * fs.superblock.payload() - 200;
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(fits as u64), ..Default::default() }).unwrap().size, fits as u64);
+    }
+
+    #[test]
+    fn large_disks_allocate_past_the_first_bitmap_block() {
+        let mut fs = with_geometry(10_000, 512);
+        let data: Vec<u8> = (0..600_000).map(|i| (i % 253) as u8).collect();
+        let inodes: Vec<u64> = (0..4).map(|n| add_file(&mut fs, 1, &format!("big{}", n), Some(data.clone()))).collect();
+        let highest = fs.inode_block_table.values().flatten().copied().max().unwrap();
+        assert!(highest > 4096, "highest block used is {}", highest);
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert!(bitmap_get(&fs.bitmap, highest));
+        for inode in inodes {
+            assert_eq!(fs.files[&inode].data.as_deref(), Some(&data[..]));
+        }
+    }
+
+    #[test]
+    fn a_full_disk_gives_enospc() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"kept".to_vec()));
+        let free = fs.free_blocks();
+        let fh = fs.open_handle(inode, libc::O_RDWR);
+
+        assert_eq!(fs.write_handle(inode, fh, 0, &vec![1; (BLOCK_COUNT * BLOCK_SIZE) as usize]), Err(libc::ENOSPC));
+        assert_eq!(fs.files[&inode].data.as_deref(), Some(&b"kept"[..]));
+        assert_eq!(fs.free_blocks(), free);
+        assert_eq!(fs.write_handle(inode, fh, i64::MAX as usize, b"far"), Err(libc::ENOSPC));
+        assert_eq!(fs.write_data(inode, usize::MAX - 1, b"far").unwrap_err().kind(), std::io::ErrorKind::FileTooLarge);
+        assert_eq!(fs.files[&inode].attrs.size, 4);
+
+        let attrs = get_default_attrs(0, 0, false);
+        let mut created = 0;
+        let err = loop {
+            match fs.make_entry(1, &format!("e{}", created), None, &attrs) {
+                Ok(_) => created += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, libc::ENOSPC);
+        assert_eq!(created as u64, free);
+        assert_eq!(fs.free_blocks(), 0);
+    }
+
+    #[test]
+    fn lookup_of_arbitrary_byte_names_never_panics() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "ñandú", None);
+        assert_eq!(fs.lookup_name(1, OsStr::new("ñandú")).unwrap().ino, inode);
+        assert_eq!(fs.lookup_name(1, OsStr::from_bytes(&[0xff, 0xfe])).map(|a| a.ino), Err(libc::EINVAL));
+        assert_eq!(fs.lookup_name(1, OsStr::from_bytes(&"ñandú".as_bytes()[..1])).map(|a| a.ino), Err(libc::EINVAL));
+        assert_eq!(fs.lookup_name(1, OsStr::new("ñ")).map(|a| a.ino), Err(ENOENT));
+
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        for _ in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let name: Vec<u8> = seed.to_le_bytes()[..(seed % 9) as usize].to_vec();
+            let expected = if std::str::from_utf8(&name).is_err() { libc::EINVAL } else { ENOENT };
+            assert_eq!(fs.lookup_name(1, OsStr::from_bytes(&name)).map(|a| a.ino), Err(expected));
+        }
+    }
+
+    #[test]
+    fn second_create_with_the_same_name_gets_eexist() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let attrs = get_default_attrs(0, 0, false);
+        let inode = fs.make_entry(1, "x", None, &attrs).unwrap().ino;
+        assert_eq!(fs.make_entry(1, "x", None, &attrs), Err(libc::EEXIST));
+        assert_eq!(fs.make_dir(1, "x", 0o755, 0, 0, 0), Err(libc::EEXIST));
+        assert_eq!(fs.find_child(1, "x"), Some(inode));
+        assert_eq!(fs.files.len(), 2);
+    }
+
+
+    #[test]
+    fn rename_flags_other_than_noreplace_are_rejected() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let a = add_file(&mut fs, 1, "a", Some(b"A".to_vec()));
+        let b = add_file(&mut fs, 1, "b", Some(b"B".to_vec()));
+
+        assert_eq!(fs.rename_entry(1, "a", 1, "b", libc::RENAME_NOREPLACE), Err(libc::EEXIST));
+        assert_eq!(fs.rename_entry(1, "a", 1, "b", libc::RENAME_EXCHANGE), Err(libc::EINVAL));
+        assert_eq!(fs.rename_entry(1, "a", 1, "b", libc::RENAME_WHITEOUT), Err(libc::EINVAL));
+        assert_eq!((fs.find_child(1, "a"), fs.find_child(1, "b")), (Some(a), Some(b)));
+        assert_eq!(fs.files[&b].data.as_deref(), Some(&b"B"[..]));
+
+        fs.rename_entry(1, "a", 1, "c", libc::RENAME_NOREPLACE).unwrap();
+        assert_eq!((fs.find_child(1, "a"), fs.find_child(1, "c")), (None, Some(a)));
+    }
+
+    #[test]
+    fn rename_replaces_files_and_empty_directories_only() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let src = add_file(&mut fs, 1, "src", Some(b"new".to_vec()));
+        let old = add_file(&mut fs, 1, "dst", Some(b"old".to_vec()));
+        let free = fs.free_blocks();
+        fs.rename_entry(1, "src", 1, "dst", 0).unwrap();
+        assert_eq!((fs.find_child(1, "src"), fs.find_child(1, "dst")), (None, Some(src)));
+        assert!(!fs.files.contains_key(&old));
+        assert_eq!(fs.free_blocks(), free + 1);
+
+        let a = add_dir(&mut fs, 1, "a");
+        let full = add_dir(&mut fs, 1, "full");
+        add_file(&mut fs, full, "inside", None);
+        let empty = add_dir(&mut fs, 1, "empty");
+        assert_eq!(fs.rename_entry(1, "a", 1, "full", 0), Err(libc::ENOTEMPTY));
+        assert_eq!(fs.rename_entry(1, "dst", 1, "empty", 0), Err(libc::EISDIR));
+        assert_eq!(fs.rename_entry(1, "a", 1, "dst", 0), Err(libc::ENOTDIR));
+        fs.rename_entry(1, "a", 1, "empty", 0).unwrap();
+        assert_eq!(fs.find_child(1, "empty"), Some(a));
+        assert!(!fs.files.contains_key(&empty));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.find_child(1, "dst"), Some(src));
+        assert_eq!(fs.find_child(1, "empty"), Some(a));
+        assert_eq!(fs.files[&1].attrs.nlink, 4);
+    }
+
+    #[test]
+    fn a_directory_cannot_move_into_its_own_subtree() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let top = add_dir(&mut fs, 1, "top");
+        let mid = add_dir(&mut fs, top, "mid");
+        add_dir(&mut fs, mid, "low");
+        let low = fs.find_child(mid, "low").unwrap();
+
+        assert_eq!(fs.rename_entry(1, "top", low, "top", 0), Err(libc::EINVAL));
+        assert_eq!(fs.rename_entry(1, "top", top, "again", 0), Err(libc::EINVAL));
+        assert_eq!(fs.find_child(1, "top"), Some(top));
+        assert_eq!(fs.files[&top].parent, 1);
+
+        fs.rename_entry(mid, "low", 1, "low", 0).unwrap();
+        assert_eq!(fs.files[&low].parent, 1);
+        assert_eq!(fs.files[&mid].attrs.nlink, 2);
+    }
+
+    #[test]
+    fn remove_subtree_removes_three_levels() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let free = fs.free_blocks();
+        let root_nlink = fs.files[&1].attrs.nlink;
+        let a = add_dir(&mut fs, 1, "a");
+        let b = add_dir(&mut fs, a, "b");
+        let c = add_dir(&mut fs, b, "c");
+        let files: Vec<u64> = [a, b, c].iter().map(|&d| add_file(&mut fs, d, "f", Some(vec![1; 700]))).collect();
+        let linked = add_file(&mut fs, c, "linked", None);
+        fs.files.get_mut(&linked).unwrap().links.push((1, fixed_name("outside")));
+        fs.files.get_mut(&1).unwrap().add_child("outside", linked);
+        fs.write_entry(linked).unwrap();
+
+        fs.remove_subtree(a);
+        for inode in [a, b, c].iter().chain(&files) {
+            assert!(!fs.files.contains_key(inode));
+            assert!(!fs.inode_block_table.contains_key(inode));
+        }
+        assert_eq!(fs.find_child(1, "a"), None);
+        assert_eq!(fs.files[&1].attrs.nlink, root_nlink);
+        assert_eq!(fs.resolve_path("outside", false), Ok(linked));
+        assert_eq!(fs.free_blocks(), free - 1);
+    }
+
+    #[test]
+    fn mknod_makes_fifos_sockets_and_devices() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let fifo = fs.make_node(1, "pipe", libc::S_IFIFO | 0o644, 0, 0, 0).unwrap().ino;
+        let sock = fs.make_node(1, "sock", libc::S_IFSOCK | 0o755, 0, 0, 0).unwrap().ino;
+        let rdev = libc::makedev(1, 3) as u32;
+        let null = fs.make_node(1, "null", libc::S_IFCHR | 0o666, rdev, 0, 0).unwrap().ino;
+        let plain = fs.make_node(1, "plain", 0o644, 0, 0, 0).unwrap();
+        assert_eq!(plain.kind, FileType::RegularFile);
+        assert_eq!(fs.make_node(1, "dir", libc::S_IFDIR | 0o755, 0, 0, 0), Err(libc::EINVAL));
+        assert_eq!(fs.make_node(1, "link", libc::S_IFLNK | 0o777, 0, 0, 0), Err(libc::EINVAL));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!((fs.files[&fifo].attrs.kind, fs.files[&fifo].attrs.perm), (FileType::NamedPipe, 0o644));
+        assert_eq!(fs.files[&sock].attrs.kind, FileType::Socket);
+        assert_eq!((fs.files[&null].attrs.kind, fs.files[&null].attrs.rdev), (FileType::CharDevice, rdev));
+    }
+
+    #[test]
+    fn xattrs_are_stored_with_the_entry() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", None);
+        let name = OsStr::new("user.comment");
+        fs.set_xattr(inode, name, b"hello", 0, 0).unwrap();
+        fs.set_xattr(inode, OsStr::new("user.empty"), b"", 0, 0).unwrap();
+        assert_eq!(fs.set_xattr(inode, name, b"again", libc::XATTR_CREATE, 0), Err(libc::EEXIST));
+        assert_eq!(fs.set_xattr(inode, OsStr::new("user.none"), b"x", libc::XATTR_REPLACE, 0), Err(libc::ENODATA));
+        assert_eq!(fs.set_xattr(inode, OsStr::new(""), b"x", 0, 0), Err(libc::EINVAL));
+        assert_eq!(fs.set_xattr(inode, name, &vec![0; XATTR_SIZE_MAX + 1], 0, 0), Err(libc::E2BIG));
+
+        let mut fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.get_xattr(inode, name), Ok(&b"hello"[..]));
+        assert_eq!(fs.get_xattr(inode, OsStr::new("user.empty")), Ok(&b""[..]));
+        let list = fs.list_xattrs(inode).unwrap();
+        let mut names: Vec<&[u8]> = list.split(|&b| b == 0).filter(|n| !n.is_empty()).collect();
+        names.sort();
+        assert_eq!(names, [&b"user.comment"[..], &b"user.empty"[..]]);
+
+        fs.remove_xattr(inode, name).unwrap();
+        assert_eq!(fs.remove_xattr(inode, name), Err(libc::ENODATA));
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.get_xattr(inode, name), Err(libc::ENODATA));
+        assert_eq!(fs.list_xattrs(inode).unwrap(), b"user.empty\0");
+    }
+
+    #[test]
+    fn a_read_only_mount_refuses_every_change() {
+        let path = std::env::temp_dir().join(format!("qrfs_read_only_test_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        initialize_new_disk(path).unwrap();
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"data".to_vec()));
+        add_dir(&mut fs, 1, "d");
+        drop(fs);
+        let before = std::fs::read(path).unwrap();
+
+        let mut fs = QRFileSystem::new_read_only(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        assert_eq!(fs.open_file(inode, libc::O_WRONLY), Err(libc::EROFS));
+        assert_eq!(fs.open_file(inode, libc::O_RDONLY | libc::O_TRUNC), Err(libc::EROFS));
+        let fh = fs.open_file(inode, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.write_handle(inode, fh, 0, b"x"), Err(libc::EROFS));
+        assert_eq!(fs.create_file(1, "new", 0o644, 0, 0, 0).map(|a| a.ino), Err(libc::EROFS));
+        assert_eq!(fs.make_dir(1, "new", 0o755, 0, 0, 0).map(|a| a.ino), Err(libc::EROFS));
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(0), ..Default::default() }).map(|a| a.ino), Err(libc::EROFS));
+        assert_eq!(fs.unlink_name(1, "f"), Err(libc::EROFS));
+        assert_eq!(fs.remove_dir(1, "d"), Err(libc::EROFS));
+        assert_eq!(fs.rename_entry(1, "f", 1, "g", 0), Err(libc::EROFS));
+        assert_eq!(fs.link_entry(inode, 1, "g").map(|a| a.ino), Err(libc::EROFS));
+        assert_eq!(fs.set_xattr(inode, OsStr::new("user.x"), b"x", 0, 0), Err(libc::EROFS));
+        assert_eq!(fs.read_data(inode, 0, 4).unwrap(), b"data");
+        fs.release_handle(inode, fh).unwrap();
+        drop(fs);
+
+        assert!(std::fs::read(path).unwrap() == before);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn readdir_with_a_small_buffer_lists_every_name_once() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        fs.max_dir_entries = 1000;
+        let dir = add_dir(&mut fs, 1, "big");
+        for n in 0..500 {
+            add_file(&mut fs, dir, &format!("child{}", n), None);
+        }
+        let fh = fs.open_dir(dir).unwrap();
+
+        let mut seen = Vec::new();
+        let mut offset = 0;
+        let mut calls = 0;
+        loop {
+            let mut room = 512;
+            let mut batch = Vec::new();
+            fs.read_dir(dir, fh, offset, |_, next_offset, _, name| {
+                let size = (24 + name.len()).next_multiple_of(8);
+                if size > room {
+                    return true;
+                }
+                room -= size;
+                batch.push((next_offset, name.to_string()));
+                false
+            }).unwrap();
+            calls += 1;
+            match batch.last() {
+                Some((next_offset, _)) => offset = *next_offset,
+                None => break,
+            }
+            seen.extend(batch.into_iter().map(|(_, name)| name));
+        }
+
+        assert!(calls > 20);
+        assert_eq!(seen.len(), 502);
+        let unique: HashSet<&String> = seen.iter().collect();
+        assert_eq!(unique.len(), 502);
+        assert!((0..500).all(|n| unique.contains(&format!("child{}", n))));
+    }
+
+    #[test]
+    fn two_loads_list_directories_the_same_way() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let dir = add_dir(&mut fs, 1, "d");
+        for n in [7, 3, 9, 1, 8, 2, 6] {
+            add_file(&mut fs, if n % 2 == 0 { dir } else { 1 }, &format!("n{}", n), None);
+        }
+        let moved = fs.find_child(1, "n9").unwrap();
+        fs.rename(1, "n9".to_string(), dir, "a_moved".to_string()).unwrap();
+        fs.unlink_entry(1, "n3", fs.find_child(1, "n3").unwrap());
+        let listing = |fs: &QRFileSystem| (fs.dir_entries(1).unwrap(), fs.dir_entries(dir).unwrap());
+        let before = listing(&fs);
+
+        let image = disk_bytes(&mut fs);
+        let first = listing(&load_image(image.clone()));
+        let second = listing(&load_image(image));
+        assert_eq!(first, second);
+        assert_eq!(first, before);
+        assert!(first.1.iter().any(|(i, _, name)| *i == moved && name == "a_moved"));
+    }
+
+    #[test]
+    fn free_blocks_follow_allocations_and_frees() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let free = fs.free_blocks();
+        assert_eq!(free, BLOCK_COUNT - fs.superblock.data_start - 1);
+
+        let inode = add_file(&mut fs, 1, "f", Some(vec![0; 2000]));
+        let chain = fs.inode_block_table[&inode].len() as u64;
+        assert_eq!(chain, 5);
+        assert_eq!(fs.free_blocks(), free - chain);
+
+        fs.set_attributes(inode, SetAttrs { size: Some(10), ..Default::default() }).unwrap();
+        assert_eq!(fs.free_blocks(), free - 1);
+
+        fs.unlink_entry(1, "f", inode);
+        assert_eq!(fs.free_blocks(), free);
+    }
+
+    #[test]
+    fn fsync_reaches_the_disk_file() {
+        let path = std::env::temp_dir().join(format!("qrfs_fsync_test_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        initialize_new_disk(path).unwrap();
+
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        fs.restore_inode_counter().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(vec![0; 10]));
+        fs.write_data(inode, 2, b"synced").unwrap();
+        assert!(fs.dirty.contains(&inode));
+        fs.flush_entry(inode).unwrap();
+        // no flush on drop, as if the process died here
+        std::mem::forget(fs);
+
+        let mut fs = QRFileSystem::new(path).unwrap();
+        fs.load_fs_from_disk().unwrap();
+        assert_eq!(&fs.files[&inode].data.as_ref().unwrap()[2..8], b"synced");
+        drop(fs);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_fresh_image_has_a_root_directory() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let root = reported_attrs(&fs.files[&1].attrs);
+        assert_eq!((root.ino, root.kind, root.nlink), (1, FileType::Directory, 2));
+        assert!(fs.inode_block_table.contains_key(&1));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&1].attrs.kind, FileType::Directory);
+        assert_eq!(fs.dir_entries(1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn a_stale_inode_counter_does_not_reuse_inodes() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let highest = (0..3).map(|n| add_file(&mut fs, 1, &format!("f{}", n), None)).max().unwrap();
+        write_u64(&mut fs.disk, INODE_COUNTER_OFFSET, 1).unwrap();
+
+        let mut fs = load_image(disk_bytes_unflushed(&fs));
+        assert_eq!(read_u64(&mut fs.disk, INODE_COUNTER_OFFSET).unwrap(), highest);
+        let fresh = add_file(&mut fs, 1, "fresh", None);
+        assert_eq!(fresh, highest + 1);
+        assert_eq!(fs.files.len(), 5);
+    }
+
+    #[test]
+    fn resolve_path_without_a_mount() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let docs = add_dir(&mut fs, 1, "docs");
+        let note = add_file(&mut fs, docs, "note.txt", None);
+
+        assert_eq!(fs.resolve_path("/", false), Ok(1));
+        assert_eq!(fs.resolve_path("/docs/note.txt", false), Ok(note));
+        assert_eq!(fs.resolve_path("docs/./../docs/note.txt", false), Ok(note));
+        assert_eq!(fs.resolve_path("/../docs/", false), Ok(docs));
+        assert_eq!(fs.resolve_path("docs/note.txt/", false), Err(libc::ENOTDIR));
+        assert_eq!(fs.resolve_path("docs/missing", false), Err(ENOENT));
+    }
+
+    #[test]
+    fn st_blocks_counts_512_byte_units() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        for (size, blocks) in [(0, 0), (1, 1), (512, 1), (1000, 2), (1025, 3)] {
+            let inode = add_file(&mut fs, 1, &format!("s{}", size), Some(vec![0; size]));
+            assert_eq!(reported_attrs(&fs.files[&inode].attrs).blocks, blocks);
+        }
+    }
+
+    #[test]
+    fn immutable_entries_refuse_changes_until_the_flag_is_cleared() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "locked", Some(b"data".to_vec()));
+        let dir = add_dir(&mut fs, 1, "dir");
+        add_file(&mut fs, 1, "other", None);
+        fs.set_attributes(inode, SetAttrs { flags: Some(FS_IMMUTABLE_FL), ..Default::default() }).unwrap();
+        fs.set_attributes(dir, SetAttrs { flags: Some(FS_IMMUTABLE_FL), ..Default::default() }).unwrap();
+
+        let mut fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.open_file(inode, libc::O_WRONLY), Err(libc::EPERM));
+        assert!(fs.open_file(inode, libc::O_RDONLY).is_ok());
+        assert_eq!(fs.set_attributes(inode, SetAttrs { size: Some(0), ..Default::default() }).map(|a| a.ino), Err(libc::EPERM));
+        assert_eq!(fs.set_attributes(inode, SetAttrs { mode: Some(0o777), ..Default::default() }).map(|a| a.ino), Err(libc::EPERM));
+        assert_eq!(fs.unlink_name(1, "locked"), Err(libc::EPERM));
+        assert_eq!(fs.link_entry(inode, 1, "alias").map(|a| a.ino), Err(libc::EPERM));
+        assert_eq!(fs.rename_entry(1, "locked", 1, "moved", 0), Err(libc::EPERM));
+        assert_eq!(fs.rename_entry(1, "other", 1, "locked", 0), Err(libc::EPERM));
+        assert_eq!(fs.remove_dir(1, "dir"), Err(libc::EPERM));
+
+        fs.set_attributes(inode, SetAttrs { flags: Some(0), ..Default::default() }).unwrap();
+        fs.unlink_name(1, "locked").unwrap();
+        assert_eq!(fs.find_child(1, "locked"), None);
+    }
+
+    #[test]
+    fn o_append_writes_at_the_end() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "log", None);
+        let fh = fs.open_handle(inode, libc::O_WRONLY | libc::O_APPEND);
+        assert_eq!(fs.write_handle(inode, fh, 0, b"a"), Ok(1));
+        assert_eq!(fs.write_handle(inode, fh, 0, b"b"), Ok(1));
+        assert_eq!(fs.files[&inode].data.as_deref(), Some(&b"ab"[..]));
+
+        let reader = fs.open_handle(inode, libc::O_RDONLY);
+        assert_eq!(fs.write_handle(inode, reader, 0, b"c"), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn release_writes_back_pending_data() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"old".to_vec()));
+        let fh = fs.open_handle(inode, libc::O_RDWR);
+        fs.write_handle(inode, fh, 0, b"new").unwrap();
+        assert_eq!(load_image(disk_bytes_unflushed(&fs)).files[&inode].data.as_deref(), Some(&b"old"[..]));
+
+        fs.release_handle(inode, fh).unwrap();
+        assert!(!fs.open_files.contains_key(&fh));
+        assert_eq!(load_image(disk_bytes_unflushed(&fs)).files[&inode].data.as_deref(), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn copy_range_copies_between_and_within_files() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let src = add_file(&mut fs, 1, "src", Some(b"0123456789".to_vec()));
+        let dst = add_file(&mut fs, 1, "dst", Some(b"abc".to_vec()));
+        let fh = fs.open_handle(dst, libc::O_WRONLY);
+
+        assert_eq!(fs.copy_range(src, 2, dst, fh, 5, 4), Ok(4));
+        assert_eq!(fs.files[&dst].data.as_deref(), Some(&b"abc\0\x002345"[..]));
+        assert_eq!(fs.copy_range(src, 8, dst, fh, 0, 100), Ok(2));
+        assert_eq!(fs.copy_range(src, 50, dst, fh, 0, 10), Ok(0));
+
+        let same = fs.open_handle(src, libc::O_RDWR);
+        assert_eq!(fs.copy_range(src, 0, src, same, 3, 6), Ok(6));
+        assert_eq!(fs.files[&src].data.as_deref(), Some(&b"0120123459"[..]));
+
+        let read_only = fs.open_handle(dst, libc::O_RDONLY);
+        assert_eq!(fs.copy_range(src, 0, dst, read_only, 0, 4), Err(libc::EBADF));
+        assert_eq!(fs.copy_range(src, 0, 1, fh, 0, 4), Err(libc::EISDIR));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&dst].data.as_deref(), Some(&b"89c\0\x002345"[..]));
+    }
+
+    #[test]
+    fn fallocate_grows_the_file_to_the_range_end() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(b"head".to_vec()));
+
+        fs.allocate_range(inode, 0, 1000, 0).unwrap();
+        assert_eq!(fs.files[&inode].attrs.size, 1000);
+        assert_eq!(&fs.files[&inode].data.as_ref().unwrap()[..6], b"head\0\0");
+        fs.allocate_range(inode, 0, 10, 0).unwrap();
+        fs.allocate_range(inode, 0, 5000, libc::FALLOC_FL_KEEP_SIZE).unwrap();
+        assert_eq!(fs.files[&inode].attrs.size, 1000);
+
+        assert_eq!(fs.allocate_range(inode, 0, 0, 0), Err(libc::EINVAL));
+        assert_eq!(fs.allocate_range(inode, 0, 10, libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE), Err(libc::EOPNOTSUPP));
+        assert_eq!(fs.allocate_range(inode, 0, i64::MAX, 0), Err(libc::ENOSPC));
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&inode].attrs.size, 1000);
+    }
+
+    #[test]
+    fn seek_data_and_seek_hole() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let inode = add_file(&mut fs, 1, "f", Some(vec![0; 100]));
+        assert_eq!(fs.seek(inode, 0, libc::SEEK_DATA), Ok(0));
+        assert_eq!(fs.seek(inode, 40, libc::SEEK_DATA), Ok(40));
+        assert_eq!(fs.seek(inode, 40, libc::SEEK_HOLE), Ok(100));
+        assert_eq!(fs.seek(inode, 100, libc::SEEK_DATA), Err(libc::ENXIO));
+        assert_eq!(fs.seek(inode, -1, libc::SEEK_HOLE), Err(libc::ENXIO));
+        assert_eq!(fs.seek(inode, 0, libc::SEEK_SET), Err(libc::EINVAL));
+
+        let empty = add_file(&mut fs, 1, "empty", None);
+        assert_eq!(fs.seek(empty, 0, libc::SEEK_HOLE), Err(libc::ENXIO));
+    }
+
+    #[test]
+    fn oldest_chained_layout_is_read_by_the_current_reader() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let sb = fs.superblock;
+        let data: Vec<u8> = (0..700).map(|i| (i % 200) as u8).collect();
+        let baseline = baseline_entry(5, 1, "old.txt", false, &data);
+        let mut v2 = baseline[..88].to_vec();
+        v2.extend_from_slice(&1u32.to_le_bytes());
+        v2.extend_from_slice(&data);
+        let blocks = [1000, 1001];
+        write_chain(&mut fs.disk, &sb, &blocks, &v2).unwrap();
+        for block in blocks {
+            bitmap_set_bit(&mut fs.bitmap, block);
+        }
+        fs.files.get_mut(&1).unwrap().attrs.nlink = 7;
+        fs.write_entry(1).unwrap();
+        write_bitmap(&mut fs.disk, &sb, &fs.bitmap).unwrap();
+        write_u64(&mut fs.disk, FORMAT_VERSION_OFFSET, 2).unwrap();
+
+        let mut fs = load_image(disk_bytes_unflushed(&fs));
+        assert_eq!(fs.format_version, FORMAT_VERSION);
+        let old = &fs.files[&5];
+        assert_eq!((old.attrs.nlink, old.attrs.uid, old.attrs.rdev, old.attrs.perm), (1, 0, 0, 0o755));
+        assert_eq!(old.data.as_deref(), Some(&data[..]));
+        assert_eq!(to_seconds(old.attrs.mtime), 1_700_000_000);
+        assert_eq!(fs.find_child(1, "old.txt"), Some(5));
+        assert_eq!(fs.files[&1].attrs.nlink, 2);
+
+        let fs = load_image(disk_bytes(&mut fs));
+        assert_eq!(fs.files[&5].data.as_deref(), Some(&data[..]));
+        assert_eq!(fs.files[&5].attrs.nlink, 1);
+    }
+
+    #[test]
+    fn block_indices_outside_the_data_region_are_rejected() {
+        let mut fs = QRFileSystem::in_memory().unwrap();
+        let sb = fs.superblock;
+        for block in [0, sb.bitmap_start, sb.data_start - 1, sb.total_blocks, u64::MAX / sb.block_size + 1] {
+            assert_eq!(read_block(&mut fs.disk, &sb, block).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(write_block(&mut fs.disk, &sb, block, &[0xff; 8]).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert!(read_block(&mut fs.disk, &sb, sb.total_blocks - 1).is_ok());
+        assert_eq!(&disk_bytes(&mut fs)[..8], &MAGIC);
+    }
+}